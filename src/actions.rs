@@ -0,0 +1,101 @@
+use crate::utils::ChatIdTrait;
+use grammers_client::grammers_tl_types as tl;
+use serde_json::{Value, json};
+
+/// Decodes service message action into a JSON object with `type` field,
+/// stored in `events.action`.
+/// Rare actions (payments, games, gifts, etc.) are kept as their debug representation.
+pub fn decode(message: &tl::types::MessageService) -> Value {
+    use tl::enums::MessageAction as A;
+    match message.action {
+        A::ChatCreate(ref a) => json!({"type": "chat_create", "title": a.title, "users": a.users}),
+        A::ChatEditTitle(ref a) => json!({"type": "chat_edit_title", "title": a.title}),
+        A::ChatEditPhoto(ref a) => json!({
+            "type": "chat_edit_photo",
+            "photo_id": match a.photo {
+                tl::enums::Photo::Photo(ref photo) => Some(photo.id),
+                tl::enums::Photo::Empty(_) => None,
+            },
+        }),
+        A::ChatDeletePhoto => json!({"type": "chat_delete_photo"}),
+        A::ChatAddUser(ref a) => json!({"type": "chat_add_user", "users": a.users}),
+        A::ChatDeleteUser(ref a) => json!({"type": "chat_delete_user", "user_id": a.user_id}),
+        A::ChatJoinedByLink(ref a) => {
+            json!({"type": "chat_joined_by_link", "inviter_id": a.inviter_id})
+        }
+        A::ChatJoinedByRequest => json!({"type": "chat_joined_by_request"}),
+        A::ChannelCreate(ref a) => json!({"type": "channel_create", "title": a.title}),
+        A::ChatMigrateTo(ref a) => json!({"type": "chat_migrate_to", "channel_id": a.channel_id}),
+        A::ChannelMigrateFrom(ref a) => json!({
+            "type": "channel_migrate_from",
+            "title": a.title,
+            "chat_id": a.chat_id,
+        }),
+        // Pinned message is the one replied to
+        A::PinMessage => json!({
+            "type": "pin_message",
+            "message_id": match message.reply_to {
+                Some(tl::enums::MessageReplyHeader::Header(ref header)) => header.reply_to_msg_id,
+                _ => None,
+            },
+        }),
+        A::HistoryClear => json!({"type": "history_clear"}),
+        A::PhoneCall(ref a) => json!({
+            "type": "phone_call",
+            "call_id": a.call_id,
+            "video": a.video,
+            "duration": a.duration,
+            "reason": a.reason.as_ref().map(|r| format!("{r:?}")),
+        }),
+        A::GroupCall(ref a) => json!({"type": "group_call", "duration": a.duration}),
+        A::GroupCallScheduled(ref a) => {
+            json!({"type": "group_call_scheduled", "schedule_date": a.schedule_date})
+        }
+        A::InviteToGroupCall(ref a) => json!({"type": "invite_to_group_call", "users": a.users}),
+        A::SetMessagesTtl(ref a) => json!({
+            "type": "set_messages_ttl",
+            "period": a.period,
+            "auto_setting_from": a.auto_setting_from,
+        }),
+        A::ScreenshotTaken => json!({"type": "screenshot_taken"}),
+        A::ContactSignUp => json!({"type": "contact_sign_up"}),
+        A::CustomAction(ref a) => json!({"type": "custom_action", "message": a.message}),
+        A::TopicCreate(ref a) => json!({
+            "type": "topic_create",
+            "title": a.title,
+            "icon_color": a.icon_color,
+            "icon_emoji_id": a.icon_emoji_id,
+        }),
+        A::TopicEdit(ref a) => json!({
+            "type": "topic_edit",
+            "title": a.title,
+            "icon_emoji_id": a.icon_emoji_id,
+            "closed": a.closed,
+            "hidden": a.hidden,
+        }),
+        ref other => json!({"type": "other", "raw": format!("{other:?}")}),
+    }
+}
+
+/// Short action name, e.g. `chat_add_user`
+pub fn name(message: &tl::types::MessageService) -> String {
+    match decode(message).get("type") {
+        Some(Value::String(name)) => name.clone(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Old group ID and new channel ID, if the message is about group migration to supergroup.
+/// Both the old group and the new channel get such a message.
+pub fn migration(message: &tl::enums::Message) -> Option<(i64, i64)> {
+    let tl::enums::Message::Service(message) = message else {
+        return None;
+    };
+    match message.action {
+        tl::enums::MessageAction::ChatMigrateTo(ref a) => Some((message.chat_id()?, a.channel_id)),
+        tl::enums::MessageAction::ChannelMigrateFrom(ref a) => {
+            Some((a.chat_id, message.chat_id()?))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,214 @@
+use crate::db::Database;
+use anyhow::{Context, Result};
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
+use std::path::Path;
+
+/// Telegram allows up to 10 media in an album
+const MAX_ALBUM_PARTS: usize = 10;
+
+/// Media messages sent together, sharing `grouped_id`
+#[derive(Serialize)]
+pub struct Album {
+    pub grouped_id: i64,
+    pub chat_id: i64,
+    /// Text of whichever part has it
+    pub caption: Option<String>,
+    pub parts: Vec<AlbumPart>,
+    /// Why the album is incomplete, see [check]
+    pub problems: Vec<String>,
+    /// Why the album might be incomplete, see [gaps]
+    pub warnings: Vec<String>,
+}
+
+/// Latest archived version of a message of the album
+#[derive(Serialize)]
+pub struct AlbumPart {
+    pub message_id: i32,
+    pub date: Option<i32>,
+    pub media_rel_path: Option<String>,
+    pub thumbnail_rel_path: Option<String>,
+    pub media_pruned: bool,
+    pub deleted: bool,
+}
+
+/// Album by its grouped ID
+pub fn album(database: &Database, media_path: &Path, grouped_id: i64) -> Result<Album> {
+    let (chat_id, caption) = database
+        .conn()
+        .query_row(
+            "SELECT chat_id, caption FROM albums WHERE grouped_id = ?1",
+            params![grouped_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .context("No album with this grouped ID")?;
+    load_album(database, media_path, grouped_id, chat_id, caption)
+}
+
+/// Every album, or albums of a single chat, oldest first
+pub fn albums(database: &Database, media_path: &Path, chat_id: Option<i64>) -> Result<Vec<Album>> {
+    let mut stmt = database.conn().prepare(
+        "SELECT grouped_id, chat_id, caption FROM albums
+         WHERE ?1 IS NULL OR chat_id = ?1 ORDER BY recorded_at, grouped_id",
+    )?;
+    let rows = stmt
+        .query_map(params![chat_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(i64, i64, Option<String>)>>>()
+        .context("Failed to load albums")?;
+    rows.into_iter()
+        .map(|(grouped_id, chat_id, caption)| {
+            load_album(database, media_path, grouped_id, chat_id, caption)
+        })
+        .collect()
+}
+
+fn load_album(
+    database: &Database,
+    media_path: &Path,
+    grouped_id: i64,
+    chat_id: i64,
+    caption: Option<String>,
+) -> Result<Album> {
+    // Chat ID is only known for deletions in channels, others are matched by message ID only,
+    // which only works for chats sharing message IDs
+    let match_deleted = !database.is_channel(chat_id);
+    let mut stmt = database.conn().prepare(
+        "SELECT e.message_id, e.date, e.media_rel_path, e.thumbnail_rel_path,
+                e.media_pruned_at IS NOT NULL,
+                EXISTS (SELECT 1 FROM events d WHERE d.type = 'message_deleted' AND d.message_id = e.message_id
+                        AND (d.chat_id = e.chat_id OR (?3 AND d.chat_id IS NULL)))
+         FROM events e
+         WHERE e.id IN (SELECT MAX(id) FROM events WHERE chat_id = ?1 AND grouped_id = ?2 GROUP BY message_id)
+         ORDER BY e.message_id",
+    )?;
+    let parts = stmt
+        .query_map(params![chat_id, grouped_id, match_deleted], |row| {
+            Ok(AlbumPart {
+                message_id: row.get(0)?,
+                date: row.get(1)?,
+                media_rel_path: row.get(2)?,
+                thumbnail_rel_path: row.get(3)?,
+                media_pruned: row.get(4)?,
+                deleted: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to load album parts")?;
+
+    let mut album = Album {
+        grouped_id,
+        chat_id,
+        caption,
+        parts,
+        problems: Vec::new(),
+        warnings: Vec::new(),
+    };
+    album.problems = check(&album, media_path);
+    // Message IDs are only consecutive in channels, elsewhere they're shared by all chats of the account,
+    // so messages of other chats received in between leave gaps too
+    let gaps = gaps(&album);
+    if database.is_channel(chat_id) {
+        album.problems.extend(gaps);
+    } else {
+        album.warnings = gaps;
+    }
+    Ok(album)
+}
+
+/// Checks that all parts of the album have their media
+pub fn check(album: &Album, media_path: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    if album.parts.len() > MAX_ALBUM_PARTS {
+        problems.push(format!("{} parts, more than possible", album.parts.len()));
+    }
+    for part in &album.parts {
+        match part.media_rel_path {
+            None => problems.push(format!("Message {} has no media", part.message_id)),
+            Some(_) if part.media_pruned => {}
+            Some(ref rel_path) if !media_path.join(rel_path).exists() => problems.push(format!(
+                "Message {}: {rel_path} is missing",
+                part.message_id
+            )),
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+/// Gaps between message IDs of the album's parts.
+/// Telegram doesn't tell how many parts an album has, but they're sent with consecutive message IDs,
+/// so in channels a gap means a part was never archived.
+pub fn gaps(album: &Album) -> Vec<String> {
+    album
+        .parts
+        .windows(2)
+        .filter(|pair| pair[1].message_id - pair[0].message_id > 1)
+        .map(|pair| {
+            format!(
+                "{} part(s) missing between messages {} and {}",
+                pair[1].message_id - pair[0].message_id - 1,
+                pair[0].message_id,
+                pair[1].message_id
+            )
+        })
+        .collect()
+}
+
+pub fn print_albums(albums: &[Album]) {
+    for album in albums {
+        let date = album.parts.first().and_then(|p| p.date).unwrap_or_default();
+        println!(
+            "Album {} in chat {} at {date}, {} parts{}",
+            album.grouped_id,
+            album.chat_id,
+            album.parts.len(),
+            if !album.problems.is_empty() {
+                ", INCOMPLETE"
+            } else if !album.warnings.is_empty() {
+                ", possibly incomplete"
+            } else {
+                ""
+            }
+        );
+        if let Some(ref caption) = album.caption {
+            println!("  Caption: {caption}");
+        }
+        for part in &album.parts {
+            let mut flags = Vec::new();
+            if part.deleted {
+                flags.push("deleted");
+            }
+            if part.media_pruned {
+                flags.push("pruned");
+            }
+            println!(
+                "  {:>10}  {}{}",
+                part.message_id,
+                part.media_rel_path.as_deref().unwrap_or("<no media>"),
+                if flags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", flags.join(", "))
+                }
+            );
+        }
+        for problem in &album.problems {
+            println!("  ! {problem}");
+        }
+        for warning in &album.warnings {
+            println!("  ? {warning}");
+        }
+    }
+    let incomplete = albums.iter().filter(|a| !a.problems.is_empty()).count();
+    let possibly_incomplete = albums
+        .iter()
+        .filter(|a| a.problems.is_empty() && !a.warnings.is_empty())
+        .count();
+    println!(
+        "{} albums, {incomplete} incomplete, {possibly_incomplete} possibly incomplete",
+        albums.len()
+    );
+}
@@ -0,0 +1,171 @@
+use crate::db::Database;
+use crate::utils::*;
+use grammers_client::grammers_tl_types as tl;
+use serde::Serialize;
+
+/// Who wrote a message, and where it was forwarded from, with names resolved against the chat cache.
+/// Names are as of the time the message was archived, and [None] if the peer wasn't known.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Attribution {
+    pub sender_id: Option<i64>,
+    pub sender_name: Option<String>,
+    /// Bot used to send the message inline
+    pub via_bot_id: Option<i64>,
+    pub via_bot_name: Option<String>,
+    /// Signature of the channel post author, if signatures are enabled
+    pub post_author: Option<String>,
+    pub forward: Option<Forward>,
+}
+
+/// Origin of a forwarded message
+#[derive(Debug, Clone, Serialize)]
+pub struct Forward {
+    /// Original sender, user or channel. Unknown if the user hides their account in forwards.
+    pub from_id: Option<i64>,
+    /// Resolved name of the original sender, or the name shown instead of a hidden account
+    pub from_name: Option<String>,
+    /// The original sender hides their account, only their name is known
+    pub hidden: bool,
+    /// Date of the original message
+    pub date: i32,
+    /// ID of the original message, if it's a channel post
+    pub channel_post: Option<i32>,
+    pub post_author: Option<String>,
+    /// Chat and message the message was saved from, for messages in Saved Messages
+    pub saved_from_peer_id: Option<i64>,
+    pub saved_from_msg_id: Option<i32>,
+    /// Imported from another messenger
+    pub imported: bool,
+}
+
+impl Attribution {
+    /// Short description for logs, e.g. `John (fwd from News) via @bot`
+    pub fn summary(&self) -> Option<String> {
+        let name =
+            |name: &Option<String>, id: Option<i64>| name.clone().or(id.map(|id| format!("#{id}")));
+        let mut parts = Vec::new();
+        parts.extend(name(&self.sender_name, self.sender_id).or(self.post_author.clone()));
+        if let Some(ref forward) = self.forward {
+            let from = name(&forward.from_name, forward.from_id);
+            parts.push(format!(
+                "(fwd from {})",
+                from.as_deref().unwrap_or("<unknown>")
+            ));
+        }
+        if let Some(via) = name(&self.via_bot_name, self.via_bot_id) {
+            parts.push(format!("via {via}"));
+        }
+        (!parts.is_empty()).then(|| parts.join(" "))
+    }
+}
+
+/// Decodes attribution of a message, [None] for empty messages
+pub fn decode(raw_message: &tl::enums::Message, database: &Database) -> Option<Attribution> {
+    let name_of = |id: i64| database.chat(id).and_then(|c| c.name()).map(str::to_owned);
+    let sender_id = raw_message.sender_id();
+    let mut attribution = Attribution {
+        sender_id,
+        sender_name: sender_id.and_then(name_of),
+        ..Default::default()
+    };
+    match raw_message {
+        tl::enums::Message::Message(message) => {
+            attribution.via_bot_id = message.via_bot_id;
+            attribution.via_bot_name = message.via_bot_id.and_then(name_of);
+            attribution.post_author = message.post_author.clone();
+            attribution.forward = message.fwd_from.as_ref().map(|fwd_from| {
+                let tl::enums::MessageFwdHeader::Header(header) = fwd_from;
+                let from_id = header.from_id.as_ref().and_then(|peer| peer.chat_id());
+                Forward {
+                    from_id,
+                    from_name: from_id.and_then(name_of).or(header.from_name.clone()),
+                    hidden: from_id.is_none() && header.from_name.is_some(),
+                    date: header.date,
+                    channel_post: header.channel_post,
+                    post_author: header.post_author.clone(),
+                    saved_from_peer_id: header.saved_from_peer.as_ref().and_then(|p| p.chat_id()),
+                    saved_from_msg_id: header.saved_from_msg_id,
+                    imported: header.imported,
+                }
+            });
+        }
+        tl::enums::Message::Service(_) => {}
+        tl::enums::Message::Empty(_) => return None,
+    }
+    Some(attribution)
+}
+
+/// Users and channels the message refers to as its sender, forward origin or inline bot
+pub fn referenced_peers(raw_message: &tl::enums::Message) -> Vec<tl::enums::Peer> {
+    let mut peers = Vec::new();
+    match raw_message {
+        tl::enums::Message::Message(message) => {
+            peers.extend(message.from_id.clone());
+            if let Some(tl::enums::MessageFwdHeader::Header(ref header)) = message.fwd_from {
+                peers.extend(header.from_id.clone());
+            }
+            if let Some(user_id) = message.via_bot_id {
+                peers.push(tl::types::PeerUser { user_id }.into());
+            }
+        }
+        tl::enums::Message::Service(message) => peers.extend(message.from_id.clone()),
+        tl::enums::Message::Empty(_) => {}
+    }
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammers_client::grammers_tl_types::{Deserializable, Identifiable, Serializable};
+
+    /// Channel post with only its author's signature, as received in `UpdateNewChannelMessage`
+    fn channel_post(channel_id: i64, id: i32, post_author: &str) -> tl::enums::Message {
+        const POST: u32 = 1 << 14;
+        const HAS_POST_AUTHOR: u32 = 1 << 16;
+        let mut bytes = tl::types::Message::CONSTRUCTOR_ID.to_bytes();
+        bytes.extend((POST | HAS_POST_AUTHOR).to_bytes());
+        bytes.extend(0u32.to_bytes()); // flags2
+        bytes.extend(id.to_bytes());
+        bytes.extend(tl::enums::Peer::Channel(tl::types::PeerChannel { channel_id }).to_bytes());
+        bytes.extend(1_700_000_000i32.to_bytes()); // date
+        bytes.extend("Hello".to_owned().to_bytes());
+        bytes.extend(post_author.to_owned().to_bytes());
+        tl::enums::Message::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn channel_post_author() {
+        let db_path = std::env::temp_dir().join(format!(
+            "tg-keeper-attribution-test-{}.sqlite",
+            std::process::id()
+        ));
+        let mut database = Database::new(&db_path, None).unwrap();
+        let message = channel_post(100, 42, "Jane");
+        assert_eq!(message.chat_id(), Some(100));
+
+        let attribution = decode(&message, &database).unwrap();
+        assert_eq!(attribution.sender_id, None);
+        assert_eq!(attribution.post_author.as_deref(), Some("Jane"));
+        assert_eq!(attribution.summary().as_deref(), Some("Jane"));
+
+        database.save_message(&message, false, None).unwrap();
+        let saved: String = database
+            .conn()
+            .query_row(
+                "SELECT attribution FROM events WHERE chat_id = 100 AND message_id = 42",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        assert_eq!(saved["post_author"], "Jane");
+
+        drop(database);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = db_path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use crate::db;
+use crate::utils::unix_timestamp;
+use anyhow::{Context, Result, ensure};
+use config::Config as AppConfig;
+use rusqlite::Connection;
+use rusqlite::backup::Backup;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+const MANIFEST_FILE: &str = "manifest.json";
+const MEDIA_DIR: &str = "media";
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Describes every file of a backup, so that it can be verified before restoring
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub created_at: i64,
+    /// Database is encrypted with the same key as the original one
+    pub encrypted: bool,
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    /// Relative to the backup directory
+    pub path: String,
+    pub size: u64,
+    /// Modification time of the original file, to detect changes on incremental backups
+    pub mtime_ms: i64,
+    pub sha256: String,
+}
+
+/// Backs up the database and media into `dest`.
+///
+/// Database is copied with SQLite online backup API, so the copy is consistent even while
+/// the keeper is writing to it. Media is copied incrementally: files unchanged since the backup
+/// already in `dest` are skipped, and files unchanged since the `link_dest` backup are hard-linked
+/// from it instead of being copied, like `rsync --link-dest` does.
+pub fn backup(
+    db_file: &Path,
+    db_key: Option<&str>,
+    media_path: &Path,
+    dest: &Path,
+    link_dest: Option<&Path>,
+) -> Result<Manifest> {
+    fs::create_dir_all(dest)
+        .with_context(|| format!("Failed to create backup directory {}", dest.display()))?;
+    let previous = index_manifest(read_manifest(dest).ok());
+    let linked = match link_dest {
+        Some(link_dest) => index_manifest(Some(read_manifest(link_dest)?)),
+        None => HashMap::new(),
+    };
+
+    let created_at = unix_timestamp();
+    let mut files = vec![backup_database(db_file, db_key, dest, created_at)?];
+
+    let mut media_files = Vec::new();
+    list_files(media_path, "", &mut media_files)?;
+    let (mut copied, mut linked_ctr) = (0, 0);
+    for rel_path in media_files {
+        let source = media_path.join(&rel_path);
+        // File might have been pruned in the meantime
+        let Ok(metadata) = source.metadata() else {
+            continue;
+        };
+        let (size, mtime_ms) = (metadata.len(), mtime_ms(&metadata));
+        let unchanged = |e: &&FileEntry| e.size == size && e.mtime_ms == mtime_ms;
+
+        let path = format!("{MEDIA_DIR}/{rel_path}");
+        let target = dest.join(&path);
+        let existing = previous.get(&path).filter(unchanged);
+        if let Some(entry) = existing.filter(|_| target.exists()) {
+            files.push(entry.clone());
+            continue;
+        }
+
+        fs::create_dir_all(target.parent().unwrap())?;
+        // Target might be hard-linked to an older backup, which must not be overwritten
+        if target.exists() {
+            fs::remove_file(&target)?;
+        }
+        let entry = match (link_dest, linked.get(&path).filter(unchanged)) {
+            (Some(link_dest), Some(entry))
+                if fs::hard_link(link_dest.join(&path), &target).is_ok() =>
+            {
+                linked_ctr += 1;
+                entry.clone()
+            }
+            _ => {
+                fs::copy(&source, &target)
+                    .with_context(|| format!("Failed to copy {}", source.display()))?;
+                copied += 1;
+                FileEntry {
+                    size: target.metadata()?.len(),
+                    mtime_ms,
+                    sha256: sha256_file(&target)?,
+                    path,
+                }
+            }
+        };
+        files.push(entry);
+    }
+    log::info!(
+        "Backed up {} files into {}: {copied} copied, {linked_ctr} hard-linked, the rest unchanged",
+        files.len(),
+        dest.display()
+    );
+
+    let manifest = Manifest {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        created_at,
+        encrypted: db_key.is_some(),
+        files,
+    };
+    let tmp_file = dest.join(format!("{MANIFEST_FILE}.tmp"));
+    fs::write(&tmp_file, serde_json::to_vec_pretty(&manifest)?)?;
+    fs::rename(&tmp_file, dest.join(MANIFEST_FILE)).context("Failed to write manifest")?;
+    Ok(manifest)
+}
+
+fn backup_database(
+    db_file: &Path,
+    db_key: Option<&str>,
+    dest: &Path,
+    created_at: i64,
+) -> Result<FileEntry> {
+    let file_name = db_file.file_name().unwrap().to_string_lossy().into_owned();
+    let tmp_file = dest.join(format!("{file_name}.tmp"));
+    if tmp_file.exists() {
+        fs::remove_file(&tmp_file).context("Failed to remove stale temporary database")?;
+    }
+
+    log::info!("Backing up database {}", db_file.display());
+    let source = db::open_connection(db_file, db_key)?;
+    {
+        let mut target = Connection::open(&tmp_file).context("Failed to create backup database")?;
+        // SQLCipher can only back up into a database with the same key
+        if let Some(key) = db_key {
+            target.pragma_update(None, "key", key)?;
+        }
+        // Copying all pages in a single step holds a read transaction, which makes it a consistent
+        // snapshot without blocking writers in WAL mode. In several steps, it would restart on every write.
+        Backup::new(&source, &mut target)?
+            .run_to_completion(-1, Duration::ZERO, None)
+            .context("Failed to back up database")?;
+        let check: String = target.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+        ensure!(check == "ok", "Backup database is corrupt: {check}");
+    }
+    fs::rename(&tmp_file, dest.join(&file_name))?;
+
+    let target = dest.join(&file_name);
+    Ok(FileEntry {
+        path: file_name,
+        size: target.metadata()?.len(),
+        mtime_ms: created_at * 1000,
+        sha256: sha256_file(&target)?,
+    })
+}
+
+/// Checks every file of the backup against its manifest.
+/// Returns descriptions of problems found, if any.
+pub fn verify(dir: &Path) -> Result<Vec<String>> {
+    let manifest = read_manifest(dir)?;
+    let mut problems = Vec::new();
+    for entry in &manifest.files {
+        let path = dir.join(&entry.path);
+        let Ok(metadata) = path.metadata() else {
+            problems.push(format!("{}: missing", entry.path));
+            continue;
+        };
+        if metadata.len() != entry.size {
+            problems.push(format!(
+                "{}: size {} doesn't match {}",
+                entry.path,
+                metadata.len(),
+                entry.size
+            ));
+        } else if sha256_file(&path)? != entry.sha256 {
+            problems.push(format!("{}: checksum mismatch", entry.path));
+        }
+    }
+    log::info!(
+        "Verified {} files of backup created at {}",
+        manifest.files.len(),
+        manifest.created_at
+    );
+    Ok(problems)
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let path = dir.join(MANIFEST_FILE);
+    let json = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&json).context("Invalid backup manifest")
+}
+
+fn index_manifest(manifest: Option<Manifest>) -> HashMap<String, FileEntry> {
+    manifest
+        .map(|m| m.files)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|e| (e.path.clone(), e))
+        .collect()
+}
+
+/// Relative paths of all files under `dir`, sorted
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    let mut entries = entries.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let rel_path = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(&entry.path(), &format!("{rel_path}/"), files)?;
+        } else if file_type.is_file() {
+            files.push(rel_path);
+        }
+    }
+    Ok(())
+}
+
+fn mtime_ms(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+//
+// Scheduled backups
+//
+
+/// Scheduled backups, configured in `[backup]` section
+#[derive(Deserialize, Clone)]
+pub struct Schedule {
+    /// Directory for snapshots
+    dest: PathBuf,
+    #[serde(default = "default_interval_secs")]
+    interval_secs: u64,
+    /// Number of snapshots to keep
+    #[serde(default = "default_keep")]
+    keep: usize,
+}
+
+fn default_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+impl Schedule {
+    /// Backup schedule, if configured
+    pub fn from_config(settings: &AppConfig) -> Result<Option<Self>> {
+        match settings.get::<Self>("backup") {
+            Ok(schedule) if schedule.interval_secs > 0 => Ok(Some(schedule)),
+            Ok(_) => Ok(None),
+            Err(config::ConfigError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e).context("Invalid backup config"),
+        }
+    }
+}
+
+/// Creates snapshots periodically while the keeper is running.
+/// Every snapshot is a full backup, with files unchanged since the previous one hard-linked.
+pub async fn run(
+    db_file: PathBuf,
+    db_key: Option<String>,
+    media_path: PathBuf,
+    schedule: Schedule,
+) -> Result<()> {
+    let interval = Duration::from_secs(schedule.interval_secs);
+    loop {
+        tokio::time::sleep(interval).await;
+        let (db_file, db_key, media_path, schedule) = (
+            db_file.clone(),
+            db_key.clone(),
+            media_path.clone(),
+            schedule.clone(),
+        );
+        let result = tokio::task::spawn_blocking(move || {
+            snapshot(&db_file, db_key.as_deref(), &media_path, &schedule)
+        })
+        .await
+        .context("Backup panicked")?;
+        match result {
+            Ok(path) => log::info!("Created backup snapshot {}", path.display()),
+            Err(e) => log::error!("Failed to create backup snapshot: {e:#}"),
+        }
+    }
+}
+
+fn snapshot(
+    db_file: &Path,
+    db_key: Option<&str>,
+    media_path: &Path,
+    schedule: &Schedule,
+) -> Result<PathBuf> {
+    fs::create_dir_all(&schedule.dest)?;
+    // Snapshots are built under a temporary name, so that unfinished ones are never used
+    for entry in fs::read_dir(&schedule.dest)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            fs::remove_dir_all(&path).context("Failed to remove unfinished snapshot")?;
+        }
+    }
+
+    let snapshots = list_snapshots(&schedule.dest)?;
+    let name = format!("{SNAPSHOT_PREFIX}{}", unix_timestamp());
+    let partial = schedule.dest.join(format!("{name}{PARTIAL_SUFFIX}"));
+    backup(
+        db_file,
+        db_key,
+        media_path,
+        &partial,
+        snapshots.last().map(PathBuf::as_path),
+    )?;
+    let path = schedule.dest.join(name);
+    fs::rename(&partial, &path)?;
+
+    let snapshots = list_snapshots(&schedule.dest)?;
+    for old in &snapshots[..snapshots.len().saturating_sub(schedule.keep)] {
+        log::info!("Removing old backup snapshot {}", old.display());
+        fs::remove_dir_all(old)?;
+    }
+    Ok(path)
+}
+
+/// Finished snapshots, oldest first
+fn list_snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut snapshots: Vec<(i64, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let timestamp = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(SNAPSHOT_PREFIX))
+            .and_then(|t| t.parse().ok());
+        if let Some(timestamp) = timestamp {
+            snapshots.push((timestamp, entry.path()));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+}
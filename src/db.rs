@@ -1,24 +1,56 @@
+use crate::actions;
+use crate::attribution;
+use crate::downloads::{DownloadKey, FailedDownload, PendingDownload, PendingStoryDownload};
+use crate::import::ImportedMessage;
+use crate::metrics::METRICS;
+use crate::render;
+use crate::sinks::{Event, EventSink, MessageEvent, StoryEvent};
+use crate::threads;
 use crate::utils::*;
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, ensure};
 use grammers_client::grammers_tl_types::{self as tl, Deserializable, Serializable};
 use grammers_client::{types, ChatMap};
-use rusqlite::{params, types::Null, Connection};
-use rusqlite_migration::{Migrations, M};
+use rusqlite::{params, types::Null, Connection, DatabaseName, OptionalExtension, Transaction};
+use rusqlite_migration::{HookResult, Migrations, M};
 use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 pub struct Database {
     conn: Connection,
-    chats: HashMap<i64, (types::Chat, Vec<u8>)>,
+    /// Latest known state of every chat, shared with the update processing by reference
+    chats: HashMap<i64, types::Chat>,
+    /// Latest known profile photo ID of every chat, [None] if it was removed
+    avatars: HashMap<i64, Option<i64>>,
+    sinks: Vec<Box<dyn EventSink>>,
+    /// Whether to store Instant View snapshots of web page previews
+    instant_view: bool,
+    /// Events of the current update, emitted once it has been committed
+    pending_events: Option<Vec<Event>>,
+    /// Rows added to `events` by the current update, counted in metrics once it has been committed
+    pending_events_saved: u64,
 }
 
 const TYPE_MESSAGE_NEW: &str = "message_new";
 const TYPE_MESSAGE_EDITED: &str = "message_edited";
 const TYPE_MESSAGE_DELETED: &str = "message_deleted";
+const TYPE_MESSAGE_IMPORTED: &str = "message_imported";
+const TYPE_STORY_NEW: &str = "story_new";
+const TYPE_STORY_EDITED: &str = "story_edited";
+const TYPE_STORY_DELETED: &str = "story_deleted";
 
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
 
-const MIGRATION_SLICE: &[M<'_>] = &[
-    M::up("CREATE TABLE IF NOT EXISTS events (
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Schema migrations. Columns added later are backfilled for archived messages by migrations of their own,
+/// appended after the ones adding them.
+fn migrations() -> Migrations<'static> {
+    Migrations::new(vec![
+        M::up("CREATE TABLE IF NOT EXISTS events (
                 id INTEGER PRIMARY KEY,
                 chat_id INTEGER,
                 message_id INTEGER NOT NULL,
@@ -27,23 +59,203 @@ const MIGRATION_SLICE: &[M<'_>] = &[
                 serialized BLOB,
                 media_rel_path TEXT
             )"),
-    M::up("CREATE TABLE IF NOT EXISTS chats (
+        M::up("CREATE TABLE IF NOT EXISTS chats (
                 chat_id INTEGER PRIMARY KEY,
                 serialized BLOB NOT NULL
             )"),
-    M::up("ALTER TABLE events ADD thumbnail_rel_path TEXT;"),
-];
-const MIGRATIONS: Migrations<'_> = Migrations::from_slice(MIGRATION_SLICE);
+        M::up("ALTER TABLE events ADD thumbnail_rel_path TEXT;"),
+        M::up("CREATE TABLE IF NOT EXISTS pending_downloads (
+                chat_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                serialized BLOB NOT NULL,
+                PRIMARY KEY (chat_id, message_id)
+            )"),
+        M::up("ALTER TABLE events ADD recorded_at INTEGER;"),
+        M::up("ALTER TABLE events ADD text TEXT;
+           ALTER TABLE events ADD sender_id INTEGER;
+           ALTER TABLE events ADD sender_name TEXT;
+           ALTER TABLE events ADD entities TEXT;
+           CREATE INDEX IF NOT EXISTS events_chat_message ON events (chat_id, message_id);"),
+        M::up("ALTER TABLE events ADD media_pruned_at INTEGER;
+           ALTER TABLE events ADD thumbnail_pruned_at INTEGER;"),
+        M::up("ALTER TABLE events ADD action TEXT;
+           CREATE TABLE IF NOT EXISTS chat_migrations (
+                chat_id INTEGER PRIMARY KEY,
+                channel_id INTEGER NOT NULL
+           );
+           CREATE VIEW IF NOT EXISTS events_merged AS
+                SELECT events.*, COALESCE(chat_migrations.channel_id, events.chat_id) AS merged_chat_id
+                FROM events LEFT JOIN chat_migrations ON chat_migrations.chat_id = events.chat_id;"),
+        M::up("CREATE TABLE IF NOT EXISTS avatars (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                photo_id INTEGER,
+                rel_path TEXT,
+                recorded_at INTEGER NOT NULL
+           );
+           CREATE INDEX IF NOT EXISTS avatars_chat ON avatars (chat_id);"),
+        M::up("CREATE TABLE IF NOT EXISTS polls (
+                poll_id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL
+           );
+           CREATE TABLE IF NOT EXISTS poll_results (
+                id INTEGER PRIMARY KEY,
+                poll_id INTEGER NOT NULL,
+                recorded_at INTEGER NOT NULL,
+                total_voters INTEGER,
+                results TEXT NOT NULL
+           );
+           CREATE INDEX IF NOT EXISTS poll_results_poll ON poll_results (poll_id);
+           CREATE TABLE IF NOT EXISTS live_locations (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                date INTEGER NOT NULL,
+                recorded_at INTEGER NOT NULL,
+                lat REAL NOT NULL,
+                long REAL NOT NULL,
+                accuracy_radius INTEGER,
+                heading INTEGER
+           );
+           CREATE INDEX IF NOT EXISTS live_locations_message ON live_locations (chat_id, message_id);"),
+        M::up("CREATE TABLE IF NOT EXISTS webpages (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                recorded_at INTEGER NOT NULL,
+                webpage_id INTEGER NOT NULL,
+                hash INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                display_url TEXT NOT NULL,
+                type TEXT,
+                site_name TEXT,
+                title TEXT,
+                description TEXT,
+                author TEXT,
+                instant_view TEXT
+           );
+           CREATE INDEX IF NOT EXISTS webpages_message ON webpages (chat_id, message_id);
+           CREATE INDEX IF NOT EXISTS webpages_url ON webpages (url);"),
+        M::up("CREATE TABLE IF NOT EXISTS stories (
+                id INTEGER PRIMARY KEY,
+                peer_id INTEGER NOT NULL,
+                story_id INTEGER NOT NULL,
+                type TEXT NOT NULL,
+                date INTEGER,
+                expire_date INTEGER,
+                serialized BLOB,
+                caption TEXT,
+                media_rel_path TEXT,
+                thumbnail_rel_path TEXT,
+                recorded_at INTEGER NOT NULL
+           );
+           CREATE INDEX IF NOT EXISTS stories_story ON stories (peer_id, story_id);"),
+        M::up("ALTER TABLE events ADD ttl_seconds INTEGER;
+           CREATE TABLE IF NOT EXISTS failed_downloads (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER,
+                message_id INTEGER,
+                rel_path TEXT NOT NULL,
+                error TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                failed_at INTEGER NOT NULL
+           );"),
+        M::up("CREATE TABLE IF NOT EXISTS sticker_sets (
+                set_id INTEGER PRIMARY KEY,
+                access_hash INTEGER NOT NULL,
+                short_name TEXT NOT NULL,
+                title TEXT NOT NULL,
+                count INTEGER NOT NULL,
+                hash INTEGER NOT NULL,
+                official INTEGER NOT NULL,
+                masks INTEGER NOT NULL,
+                emojis INTEGER NOT NULL,
+                serialized BLOB NOT NULL,
+                recorded_at INTEGER NOT NULL
+           );
+           CREATE TABLE IF NOT EXISTS stickers (
+                document_id INTEGER PRIMARY KEY,
+                set_id INTEGER,
+                emoji TEXT NOT NULL,
+                rel_path TEXT NOT NULL,
+                recorded_at INTEGER NOT NULL
+           );
+           CREATE INDEX IF NOT EXISTS stickers_set ON stickers (set_id);"),
+        M::up("ALTER TABLE events ADD grouped_id INTEGER;
+           CREATE INDEX IF NOT EXISTS events_grouped ON events (grouped_id) WHERE grouped_id IS NOT NULL;
+           CREATE TABLE IF NOT EXISTS albums (
+                grouped_id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                caption TEXT,
+                caption_message_id INTEGER,
+                recorded_at INTEGER NOT NULL
+           );
+           CREATE INDEX IF NOT EXISTS albums_chat ON albums (chat_id);"),
+        M::up("ALTER TABLE events ADD attribution TEXT;"),
+        M::up("ALTER TABLE events ADD reply_to_msg_id INTEGER;
+           ALTER TABLE events ADD reply_to_peer_id INTEGER;
+           ALTER TABLE events ADD topic_id INTEGER;
+           CREATE INDEX IF NOT EXISTS events_topic ON events (chat_id, topic_id) WHERE topic_id IS NOT NULL;
+           CREATE TABLE IF NOT EXISTS forum_topics (
+                chat_id INTEGER NOT NULL,
+                topic_id INTEGER NOT NULL,
+                title TEXT,
+                icon_color INTEGER,
+                icon_emoji_id INTEGER,
+                closed INTEGER,
+                hidden INTEGER,
+                created_at INTEGER,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, topic_id)
+           );
+           CREATE TABLE IF NOT EXISTS available_messages (
+                chat_id INTEGER NOT NULL,
+                available_min_id INTEGER NOT NULL,
+                recorded_at INTEGER NOT NULL
+           );"),
+        // Forwarded polls keep their ID, so a poll can be in many messages
+        M::up("CREATE TABLE polls_new (
+                chat_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                poll_id INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, message_id)
+           );
+           INSERT INTO polls_new (chat_id, message_id, poll_id) SELECT chat_id, message_id, poll_id FROM polls;
+           DROP TABLE polls;
+           ALTER TABLE polls_new RENAME TO polls;
+           CREATE INDEX IF NOT EXISTS polls_poll ON polls (poll_id);"),
+        M::up("CREATE TABLE IF NOT EXISTS pending_story_downloads (
+                peer_id INTEGER NOT NULL,
+                story_id INTEGER NOT NULL,
+                serialized BLOB NOT NULL,
+                PRIMARY KEY (peer_id, story_id)
+           );
+           ALTER TABLE failed_downloads ADD story_id INTEGER;"),
+        M::up("ALTER TABLE stories ADD media_pruned_at INTEGER;
+           ALTER TABLE stories ADD thumbnail_pruned_at INTEGER;"),
+        // Messages archived before albums were indexed
+        M::up_with_hook("", backfill_albums),
+        // Messages archived before replies and topics were indexed
+        M::up_with_hook("", backfill_replies),
+    ])
+}
 
 const SQL_INSERT: &str =
-    "INSERT INTO events (chat_id, message_id, date, type, serialized, media_rel_path, thumbnail_rel_path) \
-     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+    "INSERT INTO events (chat_id, message_id, date, type, serialized, media_rel_path, thumbnail_rel_path, recorded_at, text, sender_id, action, ttl_seconds, grouped_id, sender_name, attribution, reply_to_msg_id, reply_to_peer_id, topic_id) \
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)";
+
+const SQL_INSERT_IMPORTED: &str =
+    "INSERT INTO events (chat_id, message_id, date, type, media_rel_path, thumbnail_rel_path, recorded_at, text, sender_id, sender_name, entities) \
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
 
 impl Database {
-    pub fn new(db_file: &Path) -> Result<Self> {
-        let mut conn = Connection::open(db_file).context("Failed to open database connection")?;
+    pub fn new(db_file: &Path, key: Option<&str>) -> Result<Self> {
+        let mut conn = open_connection(db_file, key)?;
 
-        MIGRATIONS.to_latest(&mut conn).context("Failed to apply migrations")?;
+        migrations()
+            .to_latest(&mut conn)
+            .context("Failed to apply migrations")?;
 
         // Load chats from database
         let mut chats = HashMap::new();
@@ -62,13 +274,148 @@ impl Database {
         for row in rows {
             let (chat_id, serialized) = row.context("Failed to get chat row")?;
             let chat = deserialize_chat(&serialized).context("Failed to deserialize chat")?;
-            chats.insert(chat_id, (chat, serialized));
+            chats.insert(chat_id, chat);
         }
         drop(stmt);
 
         log::info!("Loaded {} chats from database", chats.len());
 
-        Ok(Database { conn, chats })
+        // Latest version wins
+        let avatars = conn
+            .prepare("SELECT chat_id, photo_id FROM avatars ORDER BY id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<i64, Option<i64>>>>()
+            .context("Failed to load avatars")?;
+
+        Ok(Database {
+            conn,
+            chats,
+            avatars,
+            sinks: Vec::new(),
+            instant_view: false,
+            pending_events: None,
+            pending_events_saved: 0,
+        })
+    }
+
+    /// Starts a transaction for all writes of a single update, to be committed atomically
+    /// with [Self::commit_update]. Events are only emitted to sinks after commit.
+    pub fn begin_update(&mut self) -> Result<()> {
+        self.conn
+            .execute_batch("BEGIN IMMEDIATE")
+            .context("Failed to begin transaction")?;
+        self.pending_events = Some(Vec::new());
+        Ok(())
+    }
+
+    pub fn commit_update(&mut self) -> Result<()> {
+        self.conn
+            .execute_batch("COMMIT")
+            .context("Failed to commit transaction")?;
+        METRICS.events_saved.fetch_add(
+            std::mem::take(&mut self.pending_events_saved),
+            Ordering::Relaxed,
+        );
+        for event in self.pending_events.take().unwrap_or_default() {
+            self.emit(event);
+        }
+        Ok(())
+    }
+
+    pub fn rollback_update(&mut self) {
+        self.pending_events = None;
+        self.pending_events_saved = 0;
+        if let Err(e) = self.conn.execute_batch("ROLLBACK") {
+            log::error!("Failed to roll back transaction: {e}");
+        }
+    }
+
+    /// Set sinks receiving every event after it has been committed
+    pub fn set_sinks(&mut self, sinks: Vec<Box<dyn EventSink>>) {
+        self.sinks = sinks;
+    }
+
+    /// Store Instant View snapshots of web page previews, rendered as HTML
+    pub fn set_instant_view(&mut self, enabled: bool) {
+        self.instant_view = enabled;
+    }
+
+    /// Counts rows added to `events` in metrics, deferred until commit inside an update
+    fn count_events_saved(&mut self, count: u64) {
+        if self.pending_events.is_some() {
+            self.pending_events_saved += count;
+        } else {
+            METRICS.events_saved.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Some(ref mut pending_events) = self.pending_events {
+            pending_events.push(event);
+            return;
+        }
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.send(&event) {
+                log::error!("Event sink failed: {e:#}");
+            }
+        }
+    }
+
+    /// Checks if the existing database file is encrypted, i.e. doesn't start with a plain SQLite header.
+    pub fn is_encrypted(db_file: &Path) -> Result<bool> {
+        if !db_file.exists() {
+            return Ok(false);
+        }
+        let mut header = [0u8; SQLITE_HEADER.len()];
+        let mut file = fs::File::open(db_file).context("Failed to open database file")?;
+        match file.read_exact(&mut header) {
+            Ok(()) => Ok(header != *SQLITE_HEADER),
+            // Empty database file
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e).context("Failed to read database file"),
+        }
+    }
+
+    /// Encrypts existing plaintext database in place using SQLCipher.
+    /// Database must not be in use.
+    pub fn encrypt(db_file: &Path, key: &str) -> Result<()> {
+        ensure!(db_file.exists(), "Database {} not found", db_file.display());
+        ensure!(
+            !Self::is_encrypted(db_file)?,
+            "Database {} is already encrypted",
+            db_file.display()
+        );
+
+        let tmp_file = db_file.with_extension("sqlite.encrypted");
+        if tmp_file.exists() {
+            fs::remove_file(&tmp_file).context("Failed to remove stale temporary database")?;
+        }
+
+        log::info!("Encrypting database {}", db_file.display());
+        {
+            let conn = open_connection(db_file, None)?;
+            let user_version: i32 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+            conn.execute(
+                "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+                params![tmp_file.to_string_lossy(), key],
+            )
+            .context("Failed to create encrypted database")?;
+            conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))
+                .context("Failed to export data into encrypted database")?;
+            // Migrations rely on user_version, which is not exported
+            conn.pragma_update(
+                Some(DatabaseName::Attached("encrypted")),
+                "user_version",
+                user_version,
+            )?;
+            conn.execute("DETACH DATABASE encrypted", [])?;
+        }
+
+        // Make sure the result is readable before replacing the original
+        drop(open_connection(&tmp_file, Some(key))?);
+        fs::rename(&tmp_file, db_file).context("Failed to replace database with encrypted one")?;
+        log::info!("Database encrypted successfully");
+        Ok(())
     }
 
     pub fn save_message(
@@ -78,6 +425,22 @@ impl Database {
         media: Option<DownloadedMedia>,
     ) -> Result<()> {
         let serialized = raw_message.to_bytes();
+        let action = match raw_message {
+            tl::enums::Message::Service(service) => Some(actions::decode(service)),
+            _ => None,
+        };
+
+        let grouped_id = match raw_message {
+            tl::enums::Message::Message(message) => message.grouped_id,
+            _ => None,
+        };
+        let attribution = attribution::decode(raw_message, self);
+        let attribution_json = attribution
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .context("Failed to serialize message attribution")?;
+        let reply_to = threads::reply_to(raw_message);
 
         let chat_id = raw_message.chat_id().unwrap();
         let date = raw_message.date();
@@ -98,45 +461,704 @@ impl Database {
                     serialized,
                     media.as_ref().map(|m| m.media_rel_path.as_str()),
                     media.as_ref().and_then(|m| m.thumbnail_rel_path.as_deref()),
+                    unix_timestamp(),
+                    raw_message.text(),
+                    raw_message.sender_id(),
+                    action.as_ref().map(|a| a.to_string()),
+                    media_ttl(raw_message),
+                    grouped_id,
+                    attribution.as_ref().and_then(|a| a.sender_name.as_deref()),
+                    attribution_json,
+                    reply_to.msg_id,
+                    reply_to.peer_id,
+                    reply_to.topic_id,
                 ],
             )
             .context("Failed to save message to database")?;
+        self.count_events_saved(1);
+
+        if let Some((chat_id, channel_id)) = actions::migration(raw_message) {
+            log::info!("Group {chat_id} migrated to supergroup {channel_id}");
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO chat_migrations (chat_id, channel_id) VALUES (?1, ?2)",
+                    params![chat_id, channel_id],
+                )
+                .context("Failed to save chat migration to database")?;
+        }
+
+        self.save_media_history(raw_message)?;
+        self.save_sticker(raw_message, media.as_ref())?;
+        match raw_message {
+            tl::enums::Message::Message(message) => {
+                save_album(&self.conn, message).context("Failed to save album to database")?
+            }
+            tl::enums::Message::Service(message) => {
+                save_forum_topic(&self.conn, message, reply_to.topic_id)
+                    .context("Failed to save forum topic to database")?
+            }
+            tl::enums::Message::Empty(_) => {}
+        }
+
+        let message_event = MessageEvent::new(raw_message, media.as_ref(), attribution);
+        self.emit(if is_edited {
+            Event::MessageEdited(message_event)
+        } else {
+            Event::MessageNew(message_event)
+        });
         Ok(())
     }
 
-    pub fn save_messages_deleted(&mut self, message_id: &[i32]) -> Result<()> {
-        // Chat ID is unknown!
-        let tx = self.conn.transaction()?;
+    /// Records changing media over time: poll results, live location positions and web page previews.
+    /// All arrive as edits of the original message, poll results also as separate updates.
+    fn save_media_history(&mut self, raw_message: &tl::enums::Message) -> Result<()> {
+        let tl::enums::Message::Message(message) = raw_message else {
+            return Ok(());
+        };
+        match message.media {
+            Some(tl::enums::MessageMedia::Poll(ref media)) => {
+                let tl::enums::Poll::Poll(ref poll) = media.poll;
+                self.conn
+                    .execute(
+                        "INSERT OR REPLACE INTO polls (chat_id, message_id, poll_id) \
+                         VALUES (?1, ?2, ?3)",
+                        params![message.chat_id(), message.id, poll.id],
+                    )
+                    .context("Failed to save poll to database")?;
+                self.save_poll_results(poll.id, &media.results)
+            }
+            Some(tl::enums::MessageMedia::GeoLive(ref media)) => {
+                let tl::enums::GeoPoint::Point(ref point) = media.geo else {
+                    return Ok(());
+                };
+                let date = message.edit_date.unwrap_or(message.date);
+                // Edits are re-delivered after restarts, and some don't move the point
+                let latest: Option<(i32, f64, f64)> = self
+                    .conn
+                    .query_row(
+                        "SELECT date, lat, long FROM live_locations \
+                         WHERE chat_id = ?1 AND message_id = ?2 ORDER BY id DESC LIMIT 1",
+                        params![message.chat_id(), message.id],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional()
+                    .context("Failed to load live location from database")?;
+                if latest == Some((date, point.lat, point.long)) {
+                    return Ok(());
+                }
+                self.conn
+                    .execute(
+                        "INSERT INTO live_locations \
+                         (chat_id, message_id, date, recorded_at, lat, long, accuracy_radius, heading) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        params![
+                            message.chat_id(),
+                            message.id,
+                            date,
+                            unix_timestamp(),
+                            point.lat,
+                            point.long,
+                            point.accuracy_radius,
+                            media.heading,
+                        ],
+                    )
+                    .context("Failed to save live location to database")?;
+                Ok(())
+            }
+            Some(tl::enums::MessageMedia::WebPage(ref media)) => {
+                // Pending previews have no metadata yet, they're completed with an edit
+                let tl::enums::WebPage::Page(ref webpage) = media.webpage else {
+                    return Ok(());
+                };
+                let latest: Option<(i64, i32)> = self
+                    .conn
+                    .query_row(
+                        "SELECT webpage_id, hash FROM webpages \
+                         WHERE chat_id = ?1 AND message_id = ?2 ORDER BY id DESC LIMIT 1",
+                        params![message.chat_id(), message.id],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?;
+                if latest == Some((webpage.id, webpage.hash)) {
+                    return Ok(()); // Message edited, preview unchanged
+                }
+                let instant_view = match webpage.cached_page {
+                    Some(tl::enums::Page::Page(ref page)) if self.instant_view => {
+                        Some(render::instant_view_html(page, webpage.title.as_deref()))
+                    }
+                    _ => None,
+                };
+                self.conn
+                    .execute(
+                        "INSERT INTO webpages \
+                         (chat_id, message_id, recorded_at, webpage_id, hash, url, display_url, \
+                          type, site_name, title, description, author, instant_view) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                        params![
+                            message.chat_id(),
+                            message.id,
+                            unix_timestamp(),
+                            webpage.id,
+                            webpage.hash,
+                            webpage.url,
+                            webpage.display_url,
+                            webpage.r#type,
+                            webpage.site_name,
+                            webpage.title,
+                            webpage.description,
+                            webpage.author,
+                            instant_view,
+                        ],
+                    )
+                    .context("Failed to save web page preview to database")?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the sticker in the shared store, the first time it's seen
+    fn save_sticker(
+        &mut self,
+        raw_message: &tl::enums::Message,
+        media: Option<&DownloadedMedia>,
+    ) -> Result<()> {
+        let tl::enums::Message::Message(message) = raw_message else {
+            return Ok(());
+        };
+        let (Some((document, attribute)), Some(media)) =
+            (message.media.as_ref().and_then(sticker), media)
+        else {
+            return Ok(());
+        };
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO stickers (document_id, set_id, emoji, rel_path, recorded_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    document.id,
+                    sticker_set_id(&attribute.stickerset),
+                    attribute.alt,
+                    media.media_rel_path,
+                    unix_timestamp(),
+                ],
+            )
+            .context("Failed to save sticker to database")?;
+        Ok(())
+    }
+
+    pub fn has_sticker_set(&self, set_id: i64) -> Result<bool> {
+        self.conn
+            .query_row(
+                "SELECT 1 FROM sticker_sets WHERE set_id = ?1",
+                params![set_id],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|row| row.is_some())
+            .context("Failed to look up sticker set in database")
+    }
+
+    pub fn save_sticker_set(&mut self, set: &tl::types::StickerSet) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO sticker_sets \
+                 (set_id, access_hash, short_name, title, count, hash, official, masks, emojis, \
+                  serialized, recorded_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    set.id,
+                    set.access_hash,
+                    set.short_name,
+                    set.title,
+                    set.count,
+                    set.hash,
+                    set.official,
+                    set.masks,
+                    set.emojis,
+                    tl::enums::StickerSet::from(set.clone()).to_bytes(),
+                    unix_timestamp(),
+                ],
+            )
+            .context("Failed to save sticker set to database")?;
+        Ok(())
+    }
+
+    /// Records poll vote counts, unless they haven't changed since the last time
+    pub fn save_poll_results(
+        &mut self,
+        poll_id: i64,
+        results: &tl::enums::PollResults,
+    ) -> Result<()> {
+        let tl::enums::PollResults::Results(results) = results;
+        // Vote counts might be hidden, e.g. until voting
+        let Some(ref voters) = results.results else {
+            return Ok(());
+        };
+        let voters = serde_json::Value::Array(
+            voters
+                .iter()
+                .map(|tl::enums::PollAnswerVoters::Voters(v)| {
+                    serde_json::json!({
+                        "option": hex(&v.option),
+                        "voters": v.voters,
+                        "chosen": v.chosen,
+                        "correct": v.correct,
+                    })
+                })
+                .collect(),
+        )
+        .to_string();
+
+        let latest: Option<(Option<i32>, String)> = self
+            .conn
+            .query_row(
+                "SELECT total_voters, results FROM poll_results \
+                 WHERE poll_id = ?1 ORDER BY id DESC LIMIT 1",
+                params![poll_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if latest.is_some_and(|(total, latest)| total == results.total_voters && latest == voters) {
+            return Ok(());
+        }
+        self.conn
+            .execute(
+                "INSERT INTO poll_results (poll_id, recorded_at, total_voters, results) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![poll_id, unix_timestamp(), results.total_voters, voters],
+            )
+            .context("Failed to save poll results to database")?;
+        Ok(())
+    }
+
+    /// Chat ID is only known for deletions in channels and supergroups,
+    /// elsewhere message IDs are shared by all chats of the account.
+    pub fn save_messages_deleted(
+        &mut self,
+        chat_id: Option<i64>,
+        message_id: &[i32],
+    ) -> Result<()> {
+        let tx = self.conn.savepoint()?;
         for id in message_id {
             tx.execute(
                 SQL_INSERT,
-                params![Null, id, Null, TYPE_MESSAGE_DELETED, Null, Null, Null],
+                params![
+                    chat_id,
+                    id,
+                    Null,
+                    TYPE_MESSAGE_DELETED,
+                    Null,
+                    Null,
+                    Null,
+                    unix_timestamp(),
+                    Null,
+                    Null,
+                    Null,
+                    Null,
+                    Null,
+                    Null,
+                    Null,
+                    Null,
+                    Null,
+                    Null
+                ],
             )
             .context("Failed to save message deleted to database")?;
         }
         tx.commit()?;
+        self.count_events_saved(message_id.len() as u64);
+        self.emit(Event::MessageDeleted {
+            chat_id,
+            message_ids: message_id.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Saves messages imported from another archive, all at once
+    pub fn save_imported_messages(&mut self, messages: &[ImportedMessage]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(SQL_INSERT_IMPORTED)?;
+            for message in messages {
+                stmt.execute(params![
+                    message.chat_id,
+                    message.message_id,
+                    message.date,
+                    TYPE_MESSAGE_IMPORTED,
+                    message.media.as_ref().map(|m| m.media_rel_path.as_str()),
+                    message
+                        .media
+                        .as_ref()
+                        .and_then(|m| m.thumbnail_rel_path.as_deref()),
+                    unix_timestamp(),
+                    message.text,
+                    message.sender_id,
+                    message.sender_name,
+                    message.entities,
+                ])
+                .context("Failed to save imported message to database")?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn save_failed_download(&mut self, failed: &FailedDownload) -> Result<()> {
+        let (chat_id, message_id, story_id) = match failed.key {
+            Some(DownloadKey::Message(chat_id, message_id)) => {
+                (Some(chat_id), Some(message_id), None)
+            }
+            Some(DownloadKey::Story(peer_id, story_id)) => (Some(peer_id), None, Some(story_id)),
+            None => (None, None, None),
+        };
+        self.conn
+            .execute(
+                "INSERT INTO failed_downloads (chat_id, message_id, rel_path, error, attempts, failed_at, story_id) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    chat_id,
+                    message_id,
+                    failed.rel_path,
+                    failed.error,
+                    failed.attempts,
+                    unix_timestamp(),
+                    story_id,
+                ],
+            )
+            .context("Failed to save failed download to database")?;
+        Ok(())
+    }
+
+    /// Checks if there are any events of the message in the archive
+    pub fn has_message(&self, chat_id: i64, message_id: i32) -> Result<bool> {
+        self.conn
+            .query_row(
+                "SELECT 1 FROM events WHERE chat_id = ?1 AND message_id = ?2 LIMIT 1",
+                params![chat_id, message_id],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|row| row.is_some())
+            .context("Failed to look up message in database")
+    }
+
+    /// Latest archived version of a message, if any.
+    /// If chat ID is unknown, message is looked up by its ID only, among chats other than channels,
+    /// since only they share message IDs, and their deletions come without chat ID.
+    pub fn find_latest_message(
+        &self,
+        chat_id: Option<i64>,
+        message_id: i32,
+    ) -> Result<Option<tl::enums::Message>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT chat_id, serialized FROM events \
+             WHERE message_id = ?1 AND (?2 IS NULL OR chat_id = ?2) AND serialized IS NOT NULL \
+             ORDER BY id DESC",
+        )?;
+        let mut rows = stmt
+            .query(params![message_id, chat_id])
+            .context("Failed to look up message in database")?;
+        while let Some(row) = rows.next()? {
+            let message_chat_id: Option<i64> = row.get(0)?;
+            if chat_id.is_none() && message_chat_id.is_some_and(|id| self.is_channel(id)) {
+                continue;
+            }
+            let serialized: Vec<u8> = row.get(1)?;
+            return tl::enums::Message::from_bytes(&serialized)
+                .map(Some)
+                .context("Failed to deserialize archived message");
+        }
+        Ok(None)
+    }
+
+    /// Media files of the latest archived version of a message which has them, unless they were pruned
+    pub fn latest_media(&self, chat_id: i64, message_id: i32) -> Result<Option<DownloadedMedia>> {
+        self.conn
+            .query_row(
+                "SELECT media_rel_path, CASE WHEN thumbnail_pruned_at IS NULL THEN thumbnail_rel_path END \
+                 FROM events \
+                 WHERE chat_id = ?1 AND message_id = ?2 AND media_rel_path IS NOT NULL \
+                       AND media_pruned_at IS NULL \
+                 ORDER BY id DESC LIMIT 1",
+                params![chat_id, message_id],
+                |row| {
+                    Ok(DownloadedMedia {
+                        media_rel_path: row.get(0)?,
+                        thumbnail_rel_path: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Failed to look up media of message in database")
+    }
+
+    /// Record a message as having media download(s) in progress
+    pub fn save_pending_download(&mut self, pending: &PendingDownload) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO pending_downloads (chat_id, message_id, serialized) \
+                 VALUES (?1, ?2, ?3)",
+                params![pending.chat_id, pending.message_id, pending.serialized],
+            )
+            .context("Failed to save pending download to database")?;
+        Ok(())
+    }
+
+    pub fn delete_pending_download(&mut self, chat_id: i64, message_id: i32) -> Result<()> {
+        self.conn
+            .execute(
+                "DELETE FROM pending_downloads WHERE chat_id = ?1 AND message_id = ?2",
+                params![chat_id, message_id],
+            )
+            .context("Failed to delete pending download from database")?;
+        Ok(())
+    }
+
+    /// Messages with downloads that didn't finish before the last shutdown
+    pub fn load_pending_downloads(&self) -> Result<Vec<PendingDownload>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT chat_id, message_id, serialized FROM pending_downloads")?;
+        let pending = stmt
+            .query_map([], |row| {
+                Ok(PendingDownload {
+                    chat_id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    serialized: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to load pending downloads")?;
+        Ok(pending)
+    }
+
+    /// Record a story as having media download(s) in progress
+    pub fn save_pending_story_download(&mut self, pending: &PendingStoryDownload) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO pending_story_downloads (peer_id, story_id, serialized) \
+                 VALUES (?1, ?2, ?3)",
+                params![pending.peer_id, pending.story_id, pending.serialized],
+            )
+            .context("Failed to save pending story download to database")?;
+        Ok(())
+    }
+
+    pub fn delete_pending_story_download(&mut self, peer_id: i64, story_id: i32) -> Result<()> {
+        self.conn
+            .execute(
+                "DELETE FROM pending_story_downloads WHERE peer_id = ?1 AND story_id = ?2",
+                params![peer_id, story_id],
+            )
+            .context("Failed to delete pending story download from database")?;
+        Ok(())
+    }
+
+    /// Stories with downloads that didn't finish before the last shutdown
+    pub fn load_pending_story_downloads(&self) -> Result<Vec<PendingStoryDownload>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT peer_id, story_id, serialized FROM pending_story_downloads")?;
+        let pending = stmt
+            .query_map([], |row| {
+                Ok(PendingStoryDownload {
+                    peer_id: row.get(0)?,
+                    story_id: row.get(1)?,
+                    serialized: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to load pending story downloads")?;
+        Ok(pending)
+    }
+
+    /// Raw connection, for read-only queries of offline tools
+    pub fn conn(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Raw connection, for offline tools maintaining the archive
+    pub fn conn_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    /// Cached chat, if it has ever been seen
+    pub fn chat(&self, chat_id: i64) -> Option<&types::Chat> {
+        self.chats.get(&chat_id)
+    }
+
+    /// Whether the chat is a channel or supergroup, which number messages on their own.
+    /// Chats missing from the cache are assumed not to be.
+    pub fn is_channel(&self, chat_id: i64) -> bool {
+        match self.chat(chat_id) {
+            Some(types::Chat::Channel(_)) => true,
+            Some(types::Chat::Group(group)) => matches!(
+                group.raw,
+                tl::enums::Chat::Channel(_) | tl::enums::Chat::ChannelForbidden(_)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Checks if profile photo of the chat is different from the latest archived one
+    pub fn avatar_changed(&self, chat_id: i64, photo_id: Option<i64>) -> bool {
+        self.avatars.get(&chat_id).copied().flatten() != photo_id
+    }
+
+    /// Records a new version of chat profile photo, [None] if it was removed
+    pub fn save_avatar(
+        &mut self,
+        chat_id: i64,
+        photo_id: Option<i64>,
+        rel_path: Option<&str>,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO avatars (chat_id, photo_id, rel_path, recorded_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![chat_id, photo_id, rel_path, unix_timestamp()],
+            )
+            .context("Failed to save avatar to database")?;
+        self.avatars.insert(chat_id, photo_id);
+        self.emit(Event::AvatarChanged {
+            chat_id,
+            photo_id,
+            rel_path: rel_path.map(str::to_owned),
+        });
+        Ok(())
+    }
+
+    /// Checks if the story differs in caption or media from its latest archived version.
+    /// Media is compared by photo or document ID, since file references change over time.
+    pub fn story_changed(&self, peer_id: i64, story: &tl::types::StoryItem) -> Result<bool> {
+        let serialized: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT serialized FROM stories \
+                 WHERE peer_id = ?1 AND story_id = ?2 AND serialized IS NOT NULL \
+                 ORDER BY id DESC LIMIT 1",
+                params![peer_id, story.id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to look up story in database")?;
+        let Some(serialized) = serialized else {
+            return Ok(true);
+        };
+        Ok(match tl::enums::StoryItem::from_bytes(&serialized) {
+            Ok(tl::enums::StoryItem::Item(latest)) => {
+                let media_changed =
+                    match (media_file_id(&latest.media), media_file_id(&story.media)) {
+                        (None, None) => latest.media.to_bytes() != story.media.to_bytes(),
+                        (latest_id, id) => latest_id != id,
+                    };
+                latest.caption != story.caption || media_changed
+            }
+            _ => true,
+        })
+    }
+
+    /// Saves a new story, or a new version of an archived one
+    pub fn save_story(
+        &mut self,
+        peer_id: i64,
+        story: &tl::types::StoryItem,
+        media: Option<DownloadedMedia>,
+    ) -> Result<()> {
+        let is_edited = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM stories WHERE peer_id = ?1 AND story_id = ?2 LIMIT 1",
+                params![peer_id, story.id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        let serialized = tl::enums::StoryItem::from(story.clone()).to_bytes();
+        self.conn
+            .execute(
+                "INSERT INTO stories (peer_id, story_id, type, date, expire_date, serialized, \
+                 caption, media_rel_path, thumbnail_rel_path, recorded_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    peer_id,
+                    story.id,
+                    if is_edited {
+                        TYPE_STORY_EDITED
+                    } else {
+                        TYPE_STORY_NEW
+                    },
+                    story.date,
+                    story.expire_date,
+                    serialized,
+                    story.caption,
+                    media.as_ref().map(|m| m.media_rel_path.as_str()),
+                    media.as_ref().and_then(|m| m.thumbnail_rel_path.as_deref()),
+                    unix_timestamp(),
+                ],
+            )
+            .context("Failed to save story to database")?;
+        let story_event = StoryEvent::new(peer_id, story, media.as_ref());
+        self.emit(if is_edited {
+            Event::StoryEdited(story_event)
+        } else {
+            Event::StoryNew(story_event)
+        });
         Ok(())
     }
 
-    /// Update the cached chats with new chat data
-    pub fn update_chats(&mut self, chat_map: &ChatMap) -> Result<HashMap<i64, types::Chat>> {
+    pub fn save_story_deleted(&mut self, peer_id: i64, story_id: i32) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO stories (peer_id, story_id, type, recorded_at) VALUES (?1, ?2, ?3, ?4)",
+                params![peer_id, story_id, TYPE_STORY_DELETED, unix_timestamp()],
+            )
+            .context("Failed to save story deleted to database")?;
+        self.emit(Event::StoryDeleted { peer_id, story_id });
+        Ok(())
+    }
+
+    /// Records that messages of a channel up to `available_min_id` were hidden or cleared on Telegram.
+    /// They stay in the archive.
+    pub fn save_available_messages(
+        &mut self,
+        channel_id: i64,
+        available_min_id: i32,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO available_messages (chat_id, available_min_id, recorded_at) \
+                 VALUES (?1, ?2, ?3)",
+                params![channel_id, available_min_id, unix_timestamp()],
+            )
+            .context("Failed to save available messages to database")?;
+        self.emit(Event::HistoryCleared {
+            chat_id: channel_id,
+            available_min_id,
+        });
+        Ok(())
+    }
+
+    /// Update the cached chats with new chat data.
+    /// Only chats present in the update are looked at, and only changed ones are serialized.
+    pub fn update_chats(&mut self, chat_map: &ChatMap) -> Result<()> {
         let mut updated_ctr = 0;
 
         for chat in chat_map.iter_chats() {
             let chat_id = chat.id();
-            let serialized = serialize_chat(chat);
 
             // Only update if the chat is new or different from what we have
             let should_update = self
                 .chats
                 .get(&chat_id)
-                .is_none_or(|(_, existing_serialized)| existing_serialized != &serialized);
+                .is_none_or(|existing| !same_chat(existing, chat));
 
             if should_update {
                 // TODO: Add/replace info instead of deleting it?
                 log::debug!("Updating chat {}", chat_id);
-                self.chats
-                    .insert(chat_id, (chat.clone(), serialized.clone()));
+                let serialized = serialize_chat(chat);
+                self.chats.insert(chat_id, chat.clone());
 
                 // Also update in database
                 self.conn
@@ -145,6 +1167,7 @@ impl Database {
                         params![chat_id, serialized],
                     )
                     .context("Failed to update chat in database")?;
+                self.emit(Event::chat_updated(chat));
 
                 updated_ctr += 1;
             }
@@ -154,9 +1177,174 @@ impl Database {
             log::info!("Updated {updated_ctr} chats in cache");
         }
 
-        let result = self.chats.iter().map(|(k, v)| (*k, v.0.clone())).collect();
+        Ok(())
+    }
+}
+
+/// Attaches the message to its album, if it's part of one.
+/// Album caption is the text of whichever part has it.
+fn save_album(conn: &Connection, message: &tl::types::Message) -> rusqlite::Result<()> {
+    let Some(grouped_id) = message.grouped_id else {
+        return Ok(());
+    };
+    conn.execute(
+        "INSERT OR IGNORE INTO albums (grouped_id, chat_id, recorded_at) VALUES (?1, ?2, ?3)",
+        params![grouped_id, message.chat_id(), unix_timestamp()],
+    )?;
+    if !message.message.is_empty() {
+        conn.execute(
+            "UPDATE albums SET caption = ?1, caption_message_id = ?2 WHERE grouped_id = ?3",
+            params![message.message, message.id, grouped_id],
+        )?;
+    } else {
+        // Caption removed by an edit
+        conn.execute(
+            "UPDATE albums SET caption = NULL, caption_message_id = NULL \
+             WHERE grouped_id = ?1 AND caption_message_id = ?2",
+            params![grouped_id, message.id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Tracks forum topics from their creation and edit service messages.
+/// Edits only have the changed fields, topics created before they were archived have only those.
+fn save_forum_topic(
+    conn: &Connection,
+    message: &tl::types::MessageService,
+    topic_id: Option<i32>,
+) -> rusqlite::Result<()> {
+    let (Some(chat_id), Some(topic_id)) = (message.chat_id(), topic_id) else {
+        return Ok(());
+    };
+    let (title, icon_color, icon_emoji_id, closed, hidden, created_at) = match message.action {
+        tl::enums::MessageAction::TopicCreate(ref a) => (
+            Some(a.title.as_str()),
+            Some(a.icon_color),
+            a.icon_emoji_id,
+            None,
+            None,
+            Some(message.date),
+        ),
+        tl::enums::MessageAction::TopicEdit(ref a) => (
+            a.title.as_deref(),
+            None,
+            a.icon_emoji_id,
+            a.closed,
+            a.hidden,
+            None,
+        ),
+        _ => return Ok(()),
+    };
+    conn.execute(
+        "INSERT INTO forum_topics \
+         (chat_id, topic_id, title, icon_color, icon_emoji_id, closed, hidden, created_at, updated_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
+         ON CONFLICT (chat_id, topic_id) DO UPDATE SET \
+         title = COALESCE(excluded.title, title), \
+         icon_color = COALESCE(excluded.icon_color, icon_color), \
+         icon_emoji_id = COALESCE(excluded.icon_emoji_id, icon_emoji_id), \
+         closed = COALESCE(excluded.closed, closed), \
+         hidden = COALESCE(excluded.hidden, hidden), \
+         created_at = COALESCE(excluded.created_at, created_at), \
+         updated_at = excluded.updated_at",
+        params![
+            chat_id,
+            topic_id,
+            title,
+            icon_color,
+            icon_emoji_id,
+            closed,
+            hidden,
+            created_at,
+            message.date,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Fills reply and topic columns, and forum topics, for messages archived before they were indexed
+fn backfill_replies(tx: &Transaction) -> HookResult {
+    log::info!("Indexing replies and forum topics of archived messages");
+    let mut stmt =
+        tx.prepare("SELECT id, serialized FROM events WHERE serialized IS NOT NULL ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let serialized: Vec<u8> = row.get(1)?;
+        let Ok(raw_message) = tl::enums::Message::from_bytes(&serialized) else {
+            continue;
+        };
+        let reply_to = threads::reply_to(&raw_message);
+        tx.execute(
+            "UPDATE events SET reply_to_msg_id = ?1, reply_to_peer_id = ?2, topic_id = ?3 \
+             WHERE id = ?4",
+            params![
+                reply_to.msg_id,
+                reply_to.peer_id,
+                reply_to.topic_id,
+                row.get::<_, i64>(0)?
+            ],
+        )?;
+        if let tl::enums::Message::Service(ref message) = raw_message {
+            save_forum_topic(tx, message, reply_to.topic_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fills `grouped_id` and albums for messages archived before albums were indexed
+fn backfill_albums(tx: &Transaction) -> HookResult {
+    log::info!("Indexing albums of archived messages");
+    let mut stmt =
+        tx.prepare("SELECT id, serialized FROM events WHERE serialized IS NOT NULL ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let serialized: Vec<u8> = row.get(1)?;
+        let Ok(tl::enums::Message::Message(message)) = tl::enums::Message::from_bytes(&serialized)
+        else {
+            continue;
+        };
+        let Some(grouped_id) = message.grouped_id else {
+            continue;
+        };
+        tx.execute(
+            "UPDATE events SET grouped_id = ?1 WHERE id = ?2",
+            params![grouped_id, row.get::<_, i64>(0)?],
+        )?;
+        save_album(tx, &message)?;
+    }
+    Ok(())
+}
 
-        Ok(result)
+/// Opens a database connection, unlocking it with the key if provided.
+/// Every database access should go through here.
+pub fn open_connection(db_file: &Path, key: Option<&str>) -> Result<Connection> {
+    let conn = Connection::open(db_file).context("Failed to open database connection")?;
+    if let Some(key) = key {
+        conn.pragma_update(None, "key", key)
+            .context("Failed to set database key")?;
+    }
+    // Key is not checked until the first actual read
+    conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+        .context("Failed to read database, wrong key?")?;
+    // WAL lets external readers access the database while the keeper is writing to it
+    let journal_mode =
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    ensure!(
+        journal_mode.eq_ignore_ascii_case("wal"),
+        "Failed to enable WAL mode, journal mode is {journal_mode}"
+    );
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+fn same_chat(a: &types::Chat, b: &types::Chat) -> bool {
+    match (a, b) {
+        (types::Chat::User(a), types::Chat::User(b)) => a.raw == b.raw,
+        (types::Chat::Group(a), types::Chat::Group(b)) => a.raw == b.raw,
+        (types::Chat::Channel(a), types::Chat::Channel(b)) => a.raw == b.raw,
+        _ => false,
     }
 }
 
@@ -198,3 +1386,66 @@ fn deserialize_chat(serialized: &[u8]) -> Result<types::Chat> {
         _ => unreachable!("Unknown chat type: {}", chat_type),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "tg-keeper-db-test-{}-{name}.sqlite",
+            std::process::id()
+        ))
+    }
+
+    fn remove_db(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = path.to_owned().into_os_string();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn count_events(database: &Database) -> i64 {
+        database
+            .conn()
+            .query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn wal_mode() {
+        let path = db_path("wal");
+        let conn = open_connection(&path, None).unwrap();
+        let journal_mode: String = conn
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        drop(conn);
+        remove_db(&path);
+        assert_eq!(journal_mode, "wal");
+    }
+
+    #[test]
+    fn encryption() {
+        let path = db_path("encryption");
+        let mut database = Database::new(&path, None).unwrap();
+        database.save_messages_deleted(None, &[42]).unwrap();
+        drop(database);
+        assert!(!Database::is_encrypted(&path).unwrap());
+
+        Database::encrypt(&path, "secret").unwrap();
+        let encrypted = Database::is_encrypted(&path).unwrap();
+        let encrypted_again = Database::encrypt(&path, "secret");
+        let without_key = open_connection(&path, None).map(drop);
+        let wrong_key = open_connection(&path, Some("wrong")).map(drop);
+        let events = Database::new(&path, Some("secret")).map(|database| count_events(&database));
+        remove_db(&path);
+
+        assert!(encrypted);
+        assert!(encrypted_again.is_err());
+        assert!(without_key.is_err());
+        assert!(wrong_key.is_err());
+        // user_version is kept, so migrations aren't applied again
+        assert_eq!(events.unwrap(), 1);
+    }
+}
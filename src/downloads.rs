@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio_util::task::TaskTracker;
+
+/// Message with media download(s) that haven't finished yet.
+/// Persisted in database until all downloads are finished, so that they can be resumed after restart.
+pub struct PendingDownload {
+    pub chat_id: i64,
+    pub message_id: i32,
+    /// Serialized raw message, needed to restart the download later
+    pub serialized: Vec<u8>,
+}
+
+/// Story with media download(s) that haven't finished yet, like [PendingDownload]
+pub struct PendingStoryDownload {
+    pub peer_id: i64,
+    pub story_id: i32,
+    /// Serialized raw story item
+    pub serialized: Vec<u8>,
+}
+
+/// What a download belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadKey {
+    /// Chat ID and message ID
+    Message(i64, i32),
+    /// Peer ID and story ID
+    Story(i64, i32),
+}
+
+/// Download that failed, recorded in database so it isn't silently lost
+pub struct FailedDownload {
+    pub key: Option<DownloadKey>,
+    pub rel_path: String,
+    pub error: String,
+    pub attempts: u32,
+}
+
+/// Tracks background media downloads, so that they can be awaited on shutdown,
+/// and finished ones can be removed from the database.
+#[derive(Clone, Default)]
+pub struct Downloads {
+    tracker: TaskTracker,
+    /// Keyed by relative path of the file being downloaded.
+    /// Files not belonging to a message or story (e.g. avatars) have no key.
+    pending: Arc<Mutex<HashMap<String, Option<DownloadKey>>>>,
+    /// Messages and stories with all their downloads finished, successfully or not
+    completed: Arc<Mutex<Vec<DownloadKey>>>,
+    failed: Arc<Mutex<Vec<FailedDownload>>>,
+}
+
+impl Downloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tracker(&self) -> &TaskTracker {
+        &self.tracker
+    }
+
+    pub fn started(&self, rel_path: &str, key: Option<DownloadKey>) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(rel_path.to_owned(), key);
+    }
+
+    pub fn finished(&self, rel_path: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(Some(key)) = pending.remove(rel_path) else {
+            return;
+        };
+        if !pending.values().any(|k| *k == Some(key)) {
+            self.completed.lock().unwrap().push(key);
+        }
+    }
+
+    pub fn failed(&self, failed: FailedDownload) {
+        self.failed.lock().unwrap().push(failed);
+    }
+
+    /// Checks if the file is being downloaded
+    pub fn is_downloading(&self, rel_path: &str) -> bool {
+        self.pending.lock().unwrap().contains_key(rel_path)
+    }
+
+    /// Checks if the message or story has downloads in progress
+    pub fn is_pending(&self, key: DownloadKey) -> bool {
+        self.pending.lock().unwrap().values().any(|k| *k == Some(key))
+    }
+
+    /// Messages and stories which had all their downloads finished since the last call
+    pub fn take_completed(&self) -> Vec<DownloadKey> {
+        std::mem::take(&mut *self.completed.lock().unwrap())
+    }
+
+    /// Downloads which failed since the last call
+    pub fn take_failed(&self) -> Vec<FailedDownload> {
+        std::mem::take(&mut *self.failed.lock().unwrap())
+    }
+}
@@ -0,0 +1,83 @@
+use crate::metrics::METRICS;
+use crate::watchdog::{HEALTH, Status};
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Minimal HTTP server exposing daemon internals.
+/// It only understands `GET` requests and is meant to be bound to localhost.
+pub async fn serve(addr: SocketAddr, db_file: PathBuf) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind HTTP server to {addr}"))?;
+    log::info!("Serving metrics on http://{addr}/metrics and health on http://{addr}/health");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let db_file = db_file.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &db_file).await {
+                log::debug!("HTTP request failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, db_file: &Path) -> Result<()> {
+    // Request line is all we care about, and it always fits
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    const TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+    const JSON: &str = "application/json";
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", TEXT, METRICS.render(db_file)),
+        ("GET", "/health") => match HEALTH.status() {
+            Status::Unhealthy => ("503 Service Unavailable", JSON, HEALTH.render()),
+            _ => ("200 OK", JSON, HEALTH.render()),
+        },
+        ("GET", _) => ("404 Not Found", TEXT, "Not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", TEXT, "Method not allowed\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Queries `/health` endpoint of a running keeper.
+/// Returns whether it's healthy, along with the response body.
+pub fn query_health(addr: SocketAddr) -> Result<(bool, String)> {
+    let mut stream = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+        .with_context(|| format!("Failed to connect to {addr}, is tg-keeper running?"))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write!(stream, "GET /health HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("Malformed HTTP response")?;
+    let healthy = head
+        .split_whitespace()
+        .nth(1)
+        .is_some_and(|code| code == "200");
+    Ok((healthy, body.to_owned()))
+}
@@ -0,0 +1,350 @@
+use crate::db::Database;
+use crate::utils::DownloadedMedia;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Message imported from another archive, with fields stored in queryable columns
+/// instead of `grammers` serialized format
+pub struct ImportedMessage {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub date: Option<i64>,
+    pub text: Option<String>,
+    pub sender_id: Option<i64>,
+    pub sender_name: Option<String>,
+    /// Formatting entities as JSON array
+    pub entities: Option<String>,
+    pub media: Option<DownloadedMedia>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub chats: usize,
+    pub imported: usize,
+    /// Messages that are already in the archive
+    pub skipped: usize,
+    /// Service messages are not imported
+    pub skipped_service: usize,
+    pub media_files: usize,
+    /// Media not included in the export
+    pub missing_media: usize,
+}
+
+//
+// Telegram Desktop export format
+//
+
+#[derive(Deserialize)]
+struct ExportChat {
+    name: Option<String>,
+    id: i64,
+    #[serde(default)]
+    messages: Vec<ExportMessage>,
+}
+
+#[derive(Deserialize)]
+struct ExportMessage {
+    id: i32,
+    #[serde(rename = "type")]
+    message_type: String,
+    /// Local time of the exporting machine, only used if `date_unixtime` is missing (older exports)
+    date: Option<String>,
+    date_unixtime: Option<String>,
+    from: Option<String>,
+    /// E.g. `user123456789` or `channel123456789`
+    from_id: Option<String>,
+    /// Either a plain string, or an array of plain strings and entity objects
+    #[serde(default)]
+    text: Value,
+    text_entities: Option<Value>,
+    photo: Option<String>,
+    file: Option<String>,
+    thumbnail: Option<String>,
+}
+
+/// Imports a Telegram Desktop JSON export (directory containing `result.json`).
+/// Both single chat and full account exports are supported.
+/// Messages already in the archive are skipped, so the import can be repeated safely.
+pub fn import_tdesktop(
+    database: &mut Database,
+    media_path: &Path,
+    export_dir: &Path,
+) -> Result<ImportSummary> {
+    let result_path = export_dir.join("result.json");
+    let json = fs::read(&result_path)
+        .with_context(|| format!("Failed to read {}", result_path.display()))?;
+    let mut root: Value = serde_json::from_slice(&json).context("Failed to parse result.json")?;
+
+    let chats: Vec<Value> = if root.get("messages").is_some() {
+        vec![root]
+    } else if root.get("chats").is_some() {
+        let mut chats = Vec::new();
+        for pointer in ["/chats/list", "/left_chats/list"] {
+            if let Some(Value::Array(list)) = root.pointer_mut(pointer).map(Value::take) {
+                chats.extend(list);
+            }
+        }
+        chats
+    } else {
+        bail!(
+            "{} doesn't look like a Telegram Desktop export",
+            result_path.display()
+        );
+    };
+
+    let mut summary = ImportSummary::default();
+    for chat in chats {
+        let chat: ExportChat = serde_json::from_value(chat).context("Invalid chat in export")?;
+        log::info!(
+            "Importing {} messages of {} (#{})",
+            chat.messages.len(),
+            chat.name.as_deref().unwrap_or("<no name>"),
+            chat.id
+        );
+        import_chat(database, media_path, export_dir, chat, &mut summary)?;
+        summary.chats += 1;
+    }
+    Ok(summary)
+}
+
+fn import_chat(
+    database: &mut Database,
+    media_path: &Path,
+    export_dir: &Path,
+    chat: ExportChat,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    let mut messages = Vec::with_capacity(chat.messages.len());
+    for message in chat.messages {
+        if message.message_type != "message" {
+            summary.skipped_service += 1;
+            continue;
+        }
+        if database.has_message(chat.id, message.id)? {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let media = copy_media(media_path, export_dir, chat.id, &message, summary)?;
+        let (text, entities) = text_and_entities(&message);
+        messages.push(ImportedMessage {
+            chat_id: chat.id,
+            message_id: message.id,
+            date: message_date(&message),
+            text,
+            sender_id: message.from_id.as_deref().and_then(parse_peer_id),
+            sender_name: message.from,
+            entities,
+            media,
+        });
+    }
+    summary.imported += messages.len();
+    database.save_imported_messages(&messages)
+}
+
+/// Copies message media into the usual `chat_<id>/<msg_id>.<ext>` layout.
+/// Existing files are left alone.
+fn copy_media(
+    media_path: &Path,
+    export_dir: &Path,
+    chat_id: i64,
+    message: &ExportMessage,
+    summary: &mut ImportSummary,
+) -> Result<Option<DownloadedMedia>> {
+    let Some(file) = message.photo.as_ref().or(message.file.as_ref()) else {
+        return Ok(None);
+    };
+    // Export contains a placeholder text instead of the path if media wasn't included
+    let source = export_dir.join(file);
+    if !source.is_file() {
+        summary.missing_media += 1;
+        return Ok(None);
+    }
+
+    let ext = source.extension().and_then(|s| s.to_str()).unwrap_or("bin");
+    let media_rel_path = format!("chat_{chat_id}/{}.{ext}", message.id);
+    copy_file(&source, &media_path.join(&media_rel_path))?;
+    summary.media_files += 1;
+
+    let thumbnail_rel_path = match message.thumbnail {
+        Some(ref thumbnail) if export_dir.join(thumbnail).is_file() => {
+            let thumbnail_rel_path = format!("{media_rel_path}_thumb.jpg");
+            copy_file(
+                &export_dir.join(thumbnail),
+                &media_path.join(&thumbnail_rel_path),
+            )?;
+            Some(thumbnail_rel_path)
+        }
+        _ => None,
+    };
+
+    Ok(Some(DownloadedMedia {
+        media_rel_path,
+        thumbnail_rel_path,
+    }))
+}
+
+fn copy_file(source: &Path, destination: &Path) -> Result<()> {
+    if destination.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(destination.parent().unwrap())?;
+    fs::copy(source, destination).with_context(|| {
+        format!(
+            "Failed to copy {} to {}",
+            source.display(),
+            destination.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// Plain text, and entities as JSON array of `{"type": ..., "text": ..., ...}` objects
+fn text_and_entities(message: &ExportMessage) -> (Option<String>, Option<String>) {
+    let plain = |text: &str| serde_json::json!({"type": "plain", "text": text});
+    // Older exports have no `text_entities`, entities are only found in `text`
+    let entities: Vec<Value> = match (&message.text_entities, &message.text) {
+        (Some(Value::Array(entities)), _) => entities.clone(),
+        (_, Value::String(text)) => vec![plain(text)],
+        (_, Value::Array(parts)) => parts
+            .iter()
+            .map(|part| match part {
+                Value::String(text) => plain(text),
+                part => part.clone(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let text: String = entities
+        .iter()
+        .filter_map(|e| e.get("text").and_then(Value::as_str))
+        .collect();
+    // Plain text carries no formatting
+    let entities = Some(entities)
+        .filter(|entities| {
+            entities
+                .iter()
+                .any(|e| e.get("type").and_then(Value::as_str) != Some("plain"))
+        })
+        .map(|entities| Value::Array(entities).to_string());
+    (Some(text).filter(|t| !t.is_empty()), entities)
+}
+
+fn message_date(message: &ExportMessage) -> Option<i64> {
+    if let Some(ref date) = message.date_unixtime {
+        return date.parse().ok();
+    }
+    // `2020-01-31T12:34:56`, interpreted as UTC, as time zone of the export is unknown
+    let date = message.date.as_deref()?;
+    let (ymd, hms) = date.split_once('T')?;
+    let mut ymd = ymd.splitn(3, '-').map(|s| s.parse::<i64>());
+    let mut hms = hms.splitn(3, ':').map(|s| s.parse::<i64>());
+    let (year, month, day) = (ymd.next()?.ok()?, ymd.next()?.ok()?, ymd.next()?.ok()?);
+    let (hour, minute, second) = (hms.next()?.ok()?, hms.next()?.ok()?, hms.next()?.ok()?);
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Bare ID of `user123`, `channel123` or `chat123`
+fn parse_peer_id(peer_id: &str) -> Option<i64> {
+    peer_id
+        .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(json: Value) -> ExportMessage {
+        let mut fields = serde_json::json!({"id": 1, "type": "message"});
+        fields
+            .as_object_mut()
+            .unwrap()
+            .extend(json.as_object().unwrap().clone());
+        serde_json::from_value(fields).unwrap()
+    }
+
+    #[test]
+    fn dates() {
+        let date = |json| message_date(&message(json));
+        assert_eq!(
+            date(serde_json::json!({"date": "2020-01-31T14:34:56", "date_unixtime": "1580474096"})),
+            Some(1580474096)
+        );
+        // Older exports only have local time, taken as UTC
+        assert_eq!(
+            date(serde_json::json!({"date": "2020-01-31T12:34:56"})),
+            Some(1580474096)
+        );
+        assert_eq!(
+            date(serde_json::json!({"date": "2000-02-29T00:00:00"})),
+            Some(951782400)
+        );
+        assert_eq!(
+            date(serde_json::json!({"date": "1970-01-01T00:00:00"})),
+            Some(0)
+        );
+        assert_eq!(
+            date(serde_json::json!({"date": "1969-12-31T23:59:59"})),
+            Some(-1)
+        );
+        assert_eq!(date(serde_json::json!({"date": "yesterday"})), None);
+        assert_eq!(date(serde_json::json!({})), None);
+    }
+
+    #[test]
+    fn plain_text() {
+        let (text, entities) = text_and_entities(&message(serde_json::json!({"text": "Hello"})));
+        assert_eq!(text.as_deref(), Some("Hello"));
+        assert_eq!(entities, None);
+
+        let (text, entities) = text_and_entities(&message(serde_json::json!({"text": ""})));
+        assert_eq!(text, None);
+        assert_eq!(entities, None);
+    }
+
+    #[test]
+    fn entities() {
+        let bold = serde_json::json!({"type": "bold", "text": "world"});
+        let expected = Some(
+            serde_json::json!([{"type": "plain", "text": "Hello, "}, bold, {"type": "plain", "text": "!"}])
+                .to_string(),
+        );
+
+        let (text, entities) = text_and_entities(&message(serde_json::json!({
+            "text": ["Hello, ", bold, "!"],
+            "text_entities": [{"type": "plain", "text": "Hello, "}, bold, {"type": "plain", "text": "!"}],
+        })));
+        assert_eq!(text.as_deref(), Some("Hello, world!"));
+        assert_eq!(entities, expected);
+
+        // Older exports have entities only in `text`
+        let (text, entities) = text_and_entities(&message(
+            serde_json::json!({"text": ["Hello, ", bold, "!"]}),
+        ));
+        assert_eq!(text.as_deref(), Some("Hello, world!"));
+        assert_eq!(entities, expected);
+    }
+
+    #[test]
+    fn peer_ids() {
+        assert_eq!(parse_peer_id("user123456789"), Some(123456789));
+        assert_eq!(parse_peer_id("channel1234567890"), Some(1234567890));
+        assert_eq!(parse_peer_id("chat42"), Some(42));
+        assert_eq!(parse_peer_id("user"), None);
+    }
+}
@@ -1,21 +1,46 @@
+mod actions;
+mod albums;
+mod attribution;
+mod backup;
 mod db;
+mod downloads;
+mod http;
+mod import;
+mod metrics;
+mod notify;
+mod render;
+mod retention;
+mod secrets;
+mod session;
+mod sinks;
+mod stats;
+mod threads;
+mod timeseries;
 mod utils;
+mod watchdog;
 
+use crate::downloads::{
+    DownloadKey, Downloads, FailedDownload, PendingDownload, PendingStoryDownload,
+};
+use crate::metrics::{CountingReconnect, METRICS};
+use crate::notify::{Notification, NotificationKind, Notifiers};
+use crate::session::SessionFile;
 use crate::utils::*;
 use anyhow::{Context, Result, ensure};
+use clap::{Parser, Subcommand};
 use config::Config as AppConfig;
-use grammers_client::types::Media;
-use grammers_client::{Client, Config, InitParams};
-use grammers_client::{grammers_tl_types as tl, types};
+use grammers_client::grammers_tl_types::{self as tl, Deserializable, Serializable};
+use grammers_client::types::{self, Media};
+use grammers_client::{ChatMap, Client, Config, InitParams};
 use grammers_mtsender::{FixedReconnect, InvocationError, ServerAddr};
-use grammers_session::Session;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -26,15 +51,126 @@ const DB_FILE: &str = "tg-keeper.sqlite";
 
 const DATA_DIR: &str = "data";
 const MEDIA_SUBDIR: &str = "media";
+/// Under media directory
+const AVATARS_SUBDIR: &str = "avatars";
+/// Under media directory, shared by all messages sending the same sticker
+const STICKERS_SUBDIR: &str = "stickers";
+
+// How long to wait for pending downloads on shutdown
+const SHUTDOWN_DOWNLOADS_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Self-destructing media is downloaded right away, retrying with growing delays
+const EPHEMERAL_DOWNLOAD_ATTEMPTS: u32 = 3;
+const EPHEMERAL_RETRY_DELAY: Duration = Duration::from_secs(2);
 
 // Attempt to reconnect every 5 min, unlimited tries
-static RECONNECTION_POLICY: FixedReconnect = FixedReconnect {
+static RECONNECTION_POLICY: CountingReconnect = CountingReconnect(FixedReconnect {
     attempts: usize::MAX,
     delay: Duration::from_secs(5 * 60),
-};
+});
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Watch for updates and archive them (default)
+    Run,
+    /// Encrypt existing plaintext database in place using the configured key
+    EncryptDb,
+    /// Query health of the running keeper, exit code is non-zero if it's unhealthy
+    Status,
+    /// Show statistics over the archive
+    Stats {
+        /// Output as JSON instead of tables
+        #[arg(long)]
+        json: bool,
+        /// Number of top senders to show
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+    },
+    /// Prune media according to the retention policy
+    Prune {
+        /// Only show what would be pruned
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Back up database and media, consistently even while the keeper is running
+    Backup {
+        /// Backup directory, updated incrementally if it already contains a backup
+        dest: PathBuf,
+        /// Hard-link files unchanged since this previous backup instead of copying them
+        #[arg(long)]
+        link_dest: Option<PathBuf>,
+        /// Verify existing backup against its manifest instead of creating one
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Show how results of a poll changed over time
+    PollHistory {
+        chat_id: i64,
+        message_id: i32,
+        /// Output as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the path of a shared live location
+    LocationPath {
+        chat_id: i64,
+        message_id: i32,
+        /// Output as GeoJSON `LineString` instead of a table
+        #[arg(long)]
+        geojson: bool,
+    },
+    /// Print archived messages of a chat per forum topic, with quotes of replied messages
+    Thread {
+        chat_id: i64,
+        /// Only this topic, 1 for General
+        #[arg(long)]
+        topic: Option<i32>,
+        /// Output as JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// List albums (media sent as a group) and check that all their parts are archived
+    Albums {
+        /// Only albums of this chat
+        #[arg(long)]
+        chat: Option<i64>,
+        /// Only the album with this grouped ID
+        #[arg(long, conflicts_with = "chat")]
+        grouped_id: Option<i64>,
+        /// Only albums with missing parts or media
+        #[arg(long)]
+        incomplete: bool,
+        /// Output as JSON instead of a listing
+        #[arg(long)]
+        json: bool,
+    },
+    /// Import history from other archives, skipping messages that are already archived
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImportSource {
+    /// Telegram Desktop JSON export
+    Tdesktop {
+        /// Export directory, containing `result.json`
+        dir: PathBuf,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Initialize logging
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "debug"),
@@ -42,15 +178,8 @@ async fn main() -> Result<()> {
 
     log::info!("Starting tg-keeper v{VERSION}");
 
-    let interrupted = Arc::new(AtomicBool::new(false));
-
     let data_path = Path::new(DATA_DIR);
-    let media_path = data_path.join(MEDIA_SUBDIR);
-    fs::create_dir_all(&media_path)?;
     let database_file = data_path.join(DB_FILE);
-    let session_file = data_path.join(SESSION_FILE);
-
-    let mut database = db::Database::new(&database_file)?;
 
     // Load configuration
     let config_path = PathBuf::from(CONFIG_FILE);
@@ -64,30 +193,251 @@ async fn main() -> Result<()> {
         .build()
         .context("Failed to load config file")?;
 
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(settings, data_path, &database_file).await,
+        Command::EncryptDb => {
+            let key = secrets::resolve_secret(&settings, "db_key", "Enter database key: ")?;
+            db::Database::encrypt(&database_file, &key)
+        }
+        Command::Status => {
+            let http_listen = http_listen(&settings)?
+                .context("http_listen must be set in config to query status")?;
+            let (healthy, body) = http::query_health(http_listen)?;
+            print!("{body}");
+            ensure!(healthy, "tg-keeper is unhealthy");
+            Ok(())
+        }
+        Command::Stats { json, top } => {
+            let database = open_database(&settings, &database_file)?;
+            let stats = stats::gather(&database, &data_path.join(MEDIA_SUBDIR), top)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                stats::print_table(&stats);
+            }
+            Ok(())
+        }
+        Command::PollHistory {
+            chat_id,
+            message_id,
+            json,
+        } => {
+            let database = open_database(&settings, &database_file)?;
+            let history = timeseries::poll_history(&database, chat_id, message_id)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&history)?);
+            } else {
+                timeseries::print_poll_history(&history);
+            }
+            Ok(())
+        }
+        Command::LocationPath {
+            chat_id,
+            message_id,
+            geojson,
+        } => {
+            let database = open_database(&settings, &database_file)?;
+            let points = timeseries::location_path(&database, chat_id, message_id)?;
+            if geojson {
+                let feature = timeseries::location_path_geojson(&points);
+                println!("{}", serde_json::to_string_pretty(&feature)?);
+            } else {
+                timeseries::print_location_path(&points);
+            }
+            Ok(())
+        }
+        Command::Thread {
+            chat_id,
+            topic,
+            json,
+        } => {
+            let database = open_database(&settings, &database_file)?;
+            let topics = threads::topics(&database, chat_id, topic)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&topics)?);
+            } else {
+                threads::print_topics(&topics);
+            }
+            Ok(())
+        }
+        Command::Albums {
+            chat,
+            grouped_id,
+            incomplete,
+            json,
+        } => {
+            let database = open_database(&settings, &database_file)?;
+            let media_path = data_path.join(MEDIA_SUBDIR);
+            let mut albums = match grouped_id {
+                Some(grouped_id) => vec![albums::album(&database, &media_path, grouped_id)?],
+                None => albums::albums(&database, &media_path, chat)?,
+            };
+            if incomplete {
+                albums.retain(|album| !album.problems.is_empty() || !album.warnings.is_empty());
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&albums)?);
+            } else {
+                albums::print_albums(&albums);
+            }
+            Ok(())
+        }
+        Command::Prune { dry_run } => {
+            let policy = retention::Policy::from_config(&settings)?
+                .context("[retention] must be set in config to prune")?;
+            let mut database = open_database(&settings, &database_file)?;
+            let media_path = data_path.join(MEDIA_SUBDIR);
+            let pruned = retention::prune(&mut database, &media_path, &policy, dry_run)?;
+            retention::print_report(&pruned, dry_run);
+            Ok(())
+        }
+        Command::Backup { dest, verify, .. } if verify => {
+            let problems = backup::verify(&dest)?;
+            for problem in &problems {
+                println!("{problem}");
+            }
+            ensure!(problems.is_empty(), "Backup is damaged");
+            println!("Backup is intact");
+            Ok(())
+        }
+        Command::Backup {
+            dest, link_dest, ..
+        } => {
+            ensure!(
+                database_file.exists(),
+                "Database {} not found",
+                database_file.display()
+            );
+            let db_key = database_key(&settings, &database_file)?;
+            let media_path = data_path.join(MEDIA_SUBDIR);
+            backup::backup(
+                &database_file,
+                db_key.as_deref(),
+                &media_path,
+                &dest,
+                link_dest.as_deref(),
+            )?;
+            Ok(())
+        }
+        Command::Import {
+            source: ImportSource::Tdesktop { dir },
+        } => {
+            // History may predate the archive, so the database is created if needed
+            let media_path = data_path.join(MEDIA_SUBDIR);
+            fs::create_dir_all(&media_path)?;
+            let db_key = database_key(&settings, &database_file)?;
+            let mut database = db::Database::new(&database_file, db_key.as_deref())?;
+            let summary = import::import_tdesktop(&mut database, &media_path, &dir)?;
+            log::info!(
+                "Imported {} messages from {} chats ({} media files), skipped {} already archived, \
+                 {} service messages; {} media files were not included in the export",
+                summary.imported,
+                summary.chats,
+                summary.media_files,
+                summary.skipped,
+                summary.skipped_service,
+                summary.missing_media
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Resolves the database key, if database is (or should be) encrypted.
+/// Every command that opens the database should use this.
+fn database_key(settings: &AppConfig, database_file: &Path) -> Result<Option<String>> {
+    Ok(match secrets::resolve_secret_opt(settings, "db_key")? {
+        Some(key) => Some(key),
+        None if settings.get_bool("db_encrypted").unwrap_or(false)
+            || db::Database::is_encrypted(database_file)? =>
+        {
+            Some(secrets::resolve_secret(
+                settings,
+                "db_key",
+                "Enter database key: ",
+            )?)
+        }
+        None => None,
+    })
+}
+
+/// Opens existing database for offline commands
+fn open_database(settings: &AppConfig, database_file: &Path) -> Result<db::Database> {
+    ensure!(
+        database_file.exists(),
+        "Database {} not found",
+        database_file.display()
+    );
+    let db_key = database_key(settings, database_file)?;
+    db::Database::new(database_file, db_key.as_deref())
+}
+
+fn http_listen(settings: &AppConfig) -> Result<Option<SocketAddr>> {
+    match settings.get_string("http_listen") {
+        Ok(http_listen) => Ok(Some(
+            http_listen
+                .parse::<SocketAddr>()
+                .context("Invalid http_listen format")?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+async fn run(settings: AppConfig, data_path: &Path, database_file: &Path) -> Result<()> {
+    let media_path = data_path.join(MEDIA_SUBDIR);
+    fs::create_dir_all(&media_path)?;
+    let session_path = data_path.join(SESSION_FILE);
+
+    let db_key = database_key(&settings, database_file)?;
+    let mut database = db::Database::new(database_file, db_key.as_deref())?;
+    database.set_sinks(sinks::sinks_from_config(&settings)?);
+    database.set_instant_view(settings.get_bool("webpage_instant_view").unwrap_or(false));
+
     // Get API credentials from config
     // TODO: Hardcode api/hash/addr?
     let api_id: i32 = settings
         .get("tg_api_id")
         .context("tg_api_id not found in config")?;
-    let api_hash: String = settings
-        .get("tg_api_hash")
-        .context("tg_api_hash not found in config")?;
+    let api_hash = secrets::resolve_secret(&settings, "tg_api_hash", "Enter your API hash: ")?;
     let tg_address: String = settings
         .get("tg_address")
         .context("tg_address not found in config")?;
-    let phone: String = settings
-        .get("tg_phone")
-        .context("tg_phone not found in config.toml")?;
 
     let tg_address = tg_address
         .parse::<SocketAddr>()
         .context("Invalid tg_address format")?;
 
+    // Optional HTTP endpoint for Prometheus metrics and health checks
+    if let Some(http_listen) = http_listen(&settings)? {
+        let database_file = database_file.to_owned();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(http_listen, database_file).await {
+                log::error!("HTTP server failed: {e:#}");
+            }
+        });
+    }
+
+    // Session is encrypted if passphrase is configured, or if it has been encrypted before
+    let session_passphrase = match secrets::resolve_secret_opt(&settings, "session_passphrase")? {
+        Some(passphrase) => Some(passphrase),
+        None if settings.get_bool("session_encrypted").unwrap_or(false)
+            || SessionFile::is_encrypted(&session_path)? =>
+        {
+            Some(secrets::resolve_secret(
+                &settings,
+                "session_passphrase",
+                "Enter session passphrase: ",
+            )?)
+        }
+        None => None,
+    };
+    let (session_file, session) = SessionFile::open(&session_path, session_passphrase.as_deref())?;
+
     // Create client configuration
     let config = Config {
-        session: Session::load_file_or_create(&session_file)?,
+        session,
         api_id,
-        api_hash: api_hash.clone(),
+        api_hash,
         params: InitParams {
             app_version: VERSION.to_owned(),
             catch_up: true,
@@ -105,8 +455,12 @@ async fn main() -> Result<()> {
 
     // Sign in if needed
     if !client.is_authorized().await? {
+        let phone = secrets::resolve_secret(
+            &settings,
+            "tg_phone",
+            "Enter your phone number in international format: ",
+        )?;
         log::info!("Not logged in, sending code request...");
-        log::info!("Using phone number from config: {}", phone);
         let token = client.request_login_code(&phone).await?;
         let code = prompt_password("Enter the code you received: ")?;
 
@@ -114,9 +468,11 @@ async fn main() -> Result<()> {
             Ok(user) => user,
             Err(grammers_client::client::auth::SignInError::PasswordRequired(password_token)) => {
                 log::info!("2FA is required");
-                let password: String = settings
-                    .get("tg_2fa_password")
-                    .context("tg_2fa_password not found in config.toml")?;
+                let password = secrets::resolve_secret(
+                    &settings,
+                    "tg_2fa_password",
+                    "Enter your 2FA password: ",
+                )?;
                 client.check_password(password_token, password).await?
             }
             Err(e) => return Err(e).context("Sign in failed"),
@@ -128,121 +484,822 @@ async fn main() -> Result<()> {
         log::info!("Logged in successfully as {name}");
 
         // Save the session after successful authentication
-        client.session().save_to_file(&session_file)?;
+        session_file.save(client.session())?;
     }
 
-    // Start watching for updates
-    let spawned = {
-        let interrupted = interrupted.clone();
-        let client = client.clone();
-        let session_file = session_file.clone();
-        let mut session_save_time = Instant::now();
-        log::info!("Watching for updates...");
+    let shutdown = CancellationToken::new();
+    tokio::spawn(wait_for_shutdown_signal(shutdown.clone()));
+
+    let mut keeper = Keeper {
+        database,
+        media_path,
+        client: client.clone(),
+        downloads: Downloads::new(),
+        notifiers: Notifiers::from_config(&settings)?,
+        tgs_to_json: settings.get_bool("sticker_lottie_json").unwrap_or(false),
+        failed_sticker_sets: HashSet::new(),
+        requested_peers: HashSet::new(),
+    };
+
+    // Resume downloads that didn't finish before the last shutdown
+    for pending in keeper.database.load_pending_downloads()? {
+        let raw_message = tl::enums::Message::from_bytes(&pending.serialized)
+            .context("Failed to deserialize message with pending download")?;
+        log::info!(
+            "Resuming download for message {} in chat {}",
+            pending.message_id,
+            pending.chat_id
+        );
+        let result = download_media_raw(
+            &keeper.media_path,
+            &raw_message,
+            &client,
+            &keeper.downloads,
+            keeper.tgs_to_json,
+        )
+        .await;
+        // Left pending, to be resumed on the next start
+        if let Err(e) = result {
+            log::error!(
+                "Failed to resume download for message {} in chat {}: {e:#}",
+                pending.message_id,
+                pending.chat_id
+            );
+            continue;
+        }
+        if !keeper
+            .downloads
+            .is_pending(DownloadKey::Message(pending.chat_id, pending.message_id))
+        {
+            keeper
+                .database
+                .delete_pending_download(pending.chat_id, pending.message_id)?;
+        }
+    }
+    for pending in keeper.database.load_pending_story_downloads()? {
+        let tl::enums::StoryItem::Item(story) =
+            tl::enums::StoryItem::from_bytes(&pending.serialized)
+                .context("Failed to deserialize story with pending download")?
+        else {
+            continue;
+        };
+        log::info!(
+            "Resuming download for story {} of {}",
+            pending.story_id,
+            pending.peer_id
+        );
+        if let Err(e) = keeper.download_story_media(pending.peer_id, &story) {
+            log::error!(
+                "Failed to resume download for story {} of {}: {e:#}",
+                pending.story_id,
+                pending.peer_id
+            );
+            continue;
+        }
+        if !keeper
+            .downloads
+            .is_pending(DownloadKey::Story(pending.peer_id, pending.story_id))
+        {
+            keeper
+                .database
+                .delete_pending_story_download(pending.peer_id, pending.story_id)?;
+        }
+    }
+
+    // Watch for updates stalling, 0 disables the watchdog
+    let watchdog_timeout_secs = settings.get_int("watchdog_timeout_secs").unwrap_or(15 * 60);
+    let mut watchdog = (watchdog_timeout_secs > 0).then(|| {
+        let timeout = Duration::from_secs(watchdog_timeout_secs as u64);
+        tokio::spawn(watchdog::run(client.clone(), timeout))
+    });
+
+    // Prune media periodically, if retention policy is configured
+    let retention_policy = retention::Policy::from_config(&settings)?;
+    if let Some(policy) = retention_policy.filter(|p| p.interval_secs > 0) {
+        let (database_file, db_key) = (database_file.to_owned(), db_key.clone());
+        let media_path = keeper.media_path.clone();
         tokio::spawn(async move {
-            while !interrupted.load(std::sync::atomic::Ordering::SeqCst) {
-                let (update, chats) = match client.next_raw_update().await {
-                    Ok(v) => v,
-                    Err(e) => match e {
-                        InvocationError::Rpc(e) if e.code == -500 || e.code == 500 => {
-                            // "No workers running", this is a temporary issue with Telegram servers
-                            log::warn!("Temporary issue with Telegram servers, retrying...");
-                            tokio::time::sleep(Duration::from_secs(5)).await;
-                            continue;
-                        }
-                        e => return Err(e).context("Failed to get next raw update"),
-                    }
+            if let Err(e) = retention::run(database_file, db_key, media_path, policy).await {
+                log::error!("Retention task failed: {e:#}");
+            }
+        });
+    }
+
+    // Scheduled backup snapshots, if configured
+    if let Some(schedule) = backup::Schedule::from_config(&settings)? {
+        let (database_file, db_key) = (database_file.to_owned(), db_key.clone());
+        let media_path = keeper.media_path.clone();
+        tokio::spawn(async move {
+            if let Err(e) = backup::run(database_file, db_key, media_path, schedule).await {
+                log::error!("Backup task failed: {e:#}");
+            }
+        });
+    }
+
+    // Start watching for updates
+    log::info!("Watching for updates...");
+    let mut session_save_time = Instant::now();
+    let result = loop {
+        // Only waiting for the next update is interrupted, an update that has been received
+        // is always processed completely
+        let next_update = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break Ok(()),
+            watchdog_result = async {
+                match watchdog.as_mut() {
+                    Some(watchdog) => watchdog.await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                // Watchdog only finishes if it gave up on Telegram
+                break watchdog_result.context("Watchdog panicked").and_then(|r| r);
+            }
+            next_update = client.next_raw_update() => next_update,
+        };
+        let (update, chats) = match next_update {
+            Ok(v) => v,
+            Err(e) => match e {
+                InvocationError::Rpc(e) if e.code == -500 || e.code == 500 => {
+                    // "No workers running", this is a temporary issue with Telegram servers
+                    log::warn!("Temporary issue with Telegram servers, retrying...");
+                    METRICS.server_retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+                e => break Err(e).context("Failed to get next raw update"),
+            },
+        };
+
+        if let Err(e) = keeper.handle_update(update, &chats).await {
+            break Err(e);
+        }
+
+        // Save the session every 30 seconds
+        if session_save_time.elapsed().as_secs() > 30 {
+            if let Err(e) = session_file.save(client.session()) {
+                break Err(e);
+            }
+            session_save_time = Instant::now();
+        }
+    };
+
+    // Give pending downloads a chance to finish, the rest are resumed on next start
+    let downloads = &keeper.downloads;
+    downloads.tracker().close();
+    if !downloads.tracker().is_empty() {
+        log::info!(
+            "Waiting up to {}s for {} pending download(s)...",
+            SHUTDOWN_DOWNLOADS_TIMEOUT.as_secs(),
+            downloads.tracker().len()
+        );
+    }
+    if tokio::time::timeout(SHUTDOWN_DOWNLOADS_TIMEOUT, downloads.tracker().wait())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "{} download(s) didn't finish in time, they will be resumed on next start",
+            downloads.tracker().len()
+        );
+    }
+    keeper.sync_downloads()?;
+
+    session_file.save(client.session())?;
+    drop(client);
+
+    log::info!("Stopped");
+    result
+}
+
+/// Resolves once SIGINT (Ctrl+C) or SIGTERM is received, cancelling the token
+async fn wait_for_shutdown_signal(shutdown: CancellationToken) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to set SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => log::info!("Received Ctrl+C, stopping..."),
+            _ = sigterm.recv() => log::info!("Received SIGTERM, stopping..."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to set Ctrl+C handler");
+        log::info!("Received Ctrl+C, stopping...");
+    }
+    shutdown.cancel();
+}
+
+/// Results of requests to Telegram for a message, made before the update's transaction is opened
+#[derive(Default)]
+struct Prefetched {
+    /// Users and channels the message refers to, which the update didn't include
+    chats: Option<Arc<ChatMap>>,
+    /// Sticker set seen for the first time
+    sticker_set: Option<tl::types::StickerSet>,
+    /// Self-destructing media, [None] if its download failed
+    ephemeral_media: Option<DownloadedMedia>,
+}
+
+/// Everything needed to process an update
+struct Keeper {
+    database: db::Database,
+    media_path: PathBuf,
+    client: Client,
+    downloads: Downloads,
+    notifiers: Notifiers,
+    /// Decompress animated stickers into Lottie JSON
+    tgs_to_json: bool,
+    /// Sticker sets that couldn't be fetched, not requested again until restart
+    failed_sticker_sets: HashSet<i64>,
+    /// Peers missing from updates that were requested already, not requested again until restart
+    requested_peers: HashSet<i64>,
+}
+
+impl Keeper {
+    /// Process an update, all its writes are committed atomically.
+    /// Requests to Telegram are made before the transaction, so that the database isn't locked while waiting.
+    async fn handle_update(
+        &mut self,
+        mut update: tl::enums::Update,
+        chats: &ChatMap,
+    ) -> Result<()> {
+        let prefetched = self.prefetch(&mut update).await?;
+        self.database.begin_update()?;
+        match self.handle_update_inner(update, chats, prefetched).await {
+            Ok(()) => self.database.commit_update(),
+            Err(e) => {
+                self.database.rollback_update();
+                Err(e)
+            }
+        }
+    }
+
+    async fn handle_update_inner(
+        &mut self,
+        update: tl::enums::Update,
+        chats: &ChatMap,
+        prefetched: Prefetched,
+    ) -> Result<()> {
+        METRICS.update_received(update_type_name(&update));
+        self.database.update_chats(chats)?;
+        self.download_avatars(chats)?;
+
+        match update {
+            tl::enums::Update::NewMessage(tl::types::UpdateNewMessage { message, .. })
+            | tl::enums::Update::NewChannelMessage(tl::types::UpdateNewChannelMessage {
+                message,
+                ..
+            }) => {
+                let ephemeral_media = self.save_prefetched(prefetched)?;
+                log::info!(
+                    "New message: {}",
+                    to_pretty_summary(&message, &self.database)
+                );
+
+                let media = self.download_media(&message, ephemeral_media).await?;
+
+                self.database.save_message(&message, false, media)?;
+            }
+            tl::enums::Update::EditMessage(tl::types::UpdateEditMessage { message, .. })
+            | tl::enums::Update::EditChannelMessage(tl::types::UpdateEditChannelMessage {
+                message,
+                ..
+            }) => {
+                let ephemeral_media = self.save_prefetched(prefetched)?;
+                log::info!(
+                    "Message edited: {}",
+                    to_pretty_summary(&message, &self.database)
+                );
+
+                let (chat_id, message_id) = (message.chat_id(), message.id());
+                let original = self.database.find_latest_message(chat_id, message_id)?;
+                let notification = if !self.notifiers.is_empty() {
+                    Some(Notification::new(
+                        NotificationKind::Edited,
+                        chat_id,
+                        message_id,
+                        original.as_ref(),
+                        Some(&message),
+                        &self.database,
+                    ))
+                } else {
+                    None
                 };
-                let chats = database.update_chats(&chats)?;
 
-                match update {
-                    tl::enums::Update::NewMessage(wrapper) => {
-                        log::info!(
-                            "New message: {}",
-                            to_pretty_summary(&wrapper.message, &chats)
-                        );
+                let media = match self.unchanged_media(original.as_ref(), &message)? {
+                    Some(media) => Some(media),
+                    None => self.download_media(&message, ephemeral_media).await?,
+                };
 
-                        let media = download_media_raw(&media_path, &wrapper.message, &client)
-                            .await
-                            .expect("Failed to download media");
+                self.database.save_message(&message, true, media)?;
 
-                        database.save_message(&wrapper.message, false, media)?;
-                    }
-                    tl::enums::Update::EditMessage(wrapper) => {
-                        log::info!(
-                            "Message edited: {}",
-                            to_pretty_summary(&wrapper.message, &chats)
-                        );
-
-                        // TODO: Do not redownload media if not edited
-                        let media = download_media_raw(&media_path, &wrapper.message, &client)
-                            .await
-                            .expect("Failed to download media");
-
-                        database.save_message(&wrapper.message, true, media)?;
-                    }
-                    tl::enums::Update::DeleteMessages(wrapper) => {
-                        log::info!("Message(s) deleted: {:?}", wrapper.messages);
-                        database.save_messages_deleted(&wrapper.messages)?;
-                    }
-                    _ => {
-                        log::debug!("Unhandled raw update: {:?}", update);
+                if let Some(notification) = notification {
+                    self.notifiers.dispatch(notification);
+                }
+            }
+            tl::enums::Update::DeleteMessages(wrapper) => {
+                // Chat ID is unknown!
+                self.delete_messages(None, &wrapper.messages)?;
+            }
+            tl::enums::Update::DeleteChannelMessages(wrapper) => {
+                self.delete_messages(Some(wrapper.channel_id), &wrapper.messages)?;
+            }
+            tl::enums::Update::MessagePoll(wrapper) => {
+                log::debug!("Poll {} results updated", wrapper.poll_id);
+                self.database
+                    .save_poll_results(wrapper.poll_id, &wrapper.results)?;
+            }
+            tl::enums::Update::Story(wrapper) => self.save_story(wrapper.peer, wrapper.story)?,
+            tl::enums::Update::ChannelAvailableMessages(wrapper) => {
+                log::info!(
+                    "Messages of channel {} up to {} are no longer available",
+                    wrapper.channel_id,
+                    wrapper.available_min_id
+                );
+                self.database
+                    .save_available_messages(wrapper.channel_id, wrapper.available_min_id)?;
+            }
+            _ => {
+                log::debug!("Unhandled raw update: {:?}", update);
+            }
+        }
+        self.sync_downloads()
+    }
+
+    /// Media of the archived version of an edited message, if the edit didn't replace it
+    /// and its files are still there, so that it isn't downloaded again
+    fn unchanged_media(
+        &self,
+        original: Option<&tl::enums::Message>,
+        edited: &tl::enums::Message,
+    ) -> Result<Option<DownloadedMedia>> {
+        let file_id_of = |message: &tl::enums::Message| match message {
+            tl::enums::Message::Message(message) => message.media.as_ref().and_then(media_file_id),
+            _ => None,
+        };
+        let Some(file_id) = file_id_of(edited) else {
+            return Ok(None);
+        };
+        if original.and_then(file_id_of) != Some(file_id) {
+            return Ok(None);
+        }
+        let media = self
+            .database
+            .latest_media(edited.chat_id().unwrap(), edited.id())?;
+        Ok(media.filter(|media| {
+            self.media_path.join(&media.media_rel_path).exists()
+                || self.downloads.is_downloading(&media.media_rel_path)
+        }))
+    }
+
+    /// Records deleted messages, chat ID is only known for deletions in channels
+    fn delete_messages(&mut self, chat_id: Option<i64>, message_ids: &[i32]) -> Result<()> {
+        match chat_id {
+            Some(chat_id) => log::info!("Message(s) deleted in {chat_id}: {message_ids:?}"),
+            None => log::info!("Message(s) deleted: {message_ids:?}"),
+        }
+        METRICS
+            .messages_deleted
+            .fetch_add(message_ids.len() as u64, Ordering::Relaxed);
+        self.database.save_messages_deleted(chat_id, message_ids)?;
+
+        if !self.notifiers.is_empty() {
+            for &message_id in message_ids {
+                let original = self.database.find_latest_message(chat_id, message_id)?;
+                self.notifiers.dispatch(Notification::new(
+                    NotificationKind::Deleted,
+                    chat_id,
+                    message_id,
+                    original.as_ref(),
+                    None,
+                    &self.database,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the requests to Telegram the update needs, before its transaction is opened.
+    /// Skipped stories are replaced in the update with the fetched ones.
+    async fn prefetch(&mut self, update: &mut tl::enums::Update) -> Result<Prefetched> {
+        let (raw_message, is_new) = match update {
+            tl::enums::Update::NewMessage(wrapper) => (&wrapper.message, true),
+            tl::enums::Update::NewChannelMessage(wrapper) => (&wrapper.message, true),
+            tl::enums::Update::EditMessage(wrapper) => (&wrapper.message, false),
+            tl::enums::Update::EditChannelMessage(wrapper) => (&wrapper.message, false),
+            tl::enums::Update::Story(wrapper) => {
+                if let tl::enums::StoryItem::Skipped(ref skipped) = wrapper.story {
+                    let peer_id = wrapper.peer.chat_id().unwrap();
+                    if let Some(story) = self.fetch_story(peer_id, skipped.id).await? {
+                        wrapper.story = story;
                     }
                 }
+                return Ok(Prefetched::default());
+            }
+            _ => return Ok(Prefetched::default()),
+        };
+        let mut prefetched = Prefetched::default();
+        match self.fetch_missing_peers(raw_message).await {
+            Ok(chats) => prefetched.chats = chats,
+            // Likely to fail again, e.g. for deleted accounts, so it's not retried
+            Err(e) => log::debug!("Failed to fetch senders of message: {e:#}"),
+        }
+        match self.fetch_sticker_set(raw_message).await {
+            Ok(set) => prefetched.sticker_set = set,
+            Err(e) => log::warn!("Failed to archive sticker set: {e:#}"),
+        }
+        if let Some(ttl_seconds) = media_ttl(raw_message) {
+            prefetched.ephemeral_media = self
+                .download_ephemeral_media(raw_message, ttl_seconds, is_new)
+                .await;
+        }
+        Ok(prefetched)
+    }
+
+    /// Saves what was fetched for a message, returning its self-destructing media, if it was downloaded
+    fn save_prefetched(&mut self, prefetched: Prefetched) -> Result<Option<DownloadedMedia>> {
+        if let Some(ref chats) = prefetched.chats {
+            self.database.update_chats(chats)?;
+            self.download_avatars(chats)?;
+        }
+        if let Some(ref set) = prefetched.sticker_set {
+            self.database.save_sticker_set(set)?;
+        }
+        Ok(prefetched.ephemeral_media)
+    }
 
-                // Save the session every 30 seconds
-                if session_save_time.elapsed().as_secs() > 30 {
-                    client.session().save_to_file(&session_file)?;
-                    session_save_time = Instant::now();
+    /// Starts media download, recording it as pending until it's finished.
+    /// Self-destructing media has been downloaded already, see [Self::prefetch].
+    async fn download_media(
+        &mut self,
+        raw_message: &tl::enums::Message,
+        ephemeral_media: Option<DownloadedMedia>,
+    ) -> Result<Option<DownloadedMedia>> {
+        if media_ttl(raw_message).is_some() {
+            return Ok(ephemeral_media);
+        }
+        let media = download_media_raw(
+            &self.media_path,
+            raw_message,
+            &self.client,
+            &self.downloads,
+            self.tgs_to_json,
+        )
+        .await
+        .context("Failed to download media")?;
+        if media.is_some() {
+            let (chat_id, message_id) = (raw_message.chat_id().unwrap(), raw_message.id());
+            // Some media is saved locally without downloading
+            if self
+                .downloads
+                .is_pending(DownloadKey::Message(chat_id, message_id))
+            {
+                self.database.save_pending_download(&PendingDownload {
+                    chat_id,
+                    message_id,
+                    serialized: raw_message.to_bytes(),
+                })?;
+            }
+        }
+        Ok(media)
+    }
+
+    /// Downloads self-destructing media before the message is saved, instead of in background,
+    /// since its file reference expires soon. Failure is recorded in `failed_downloads`.
+    /// Edits reuse the file downloaded for the new message, and never download it again.
+    async fn download_ephemeral_media(
+        &mut self,
+        raw_message: &tl::enums::Message,
+        ttl_seconds: i32,
+        is_new: bool,
+    ) -> Option<DownloadedMedia> {
+        let tl::enums::Message::Message(message) = raw_message else {
+            return None;
+        };
+        let (chat_id, message_id) = (message.chat_id().unwrap(), message.id);
+        let downloadables = message
+            .media
+            .clone()
+            .and_then(Media::from_raw)
+            .and_then(media_downloadables);
+        let Some((media_ext, media_dl, _)) = downloadables else {
+            // Photo or document is gone from the message once the media has expired
+            if is_new {
+                log::error!(
+                    "Self-destructing media of message {message_id} in chat {chat_id} has already expired"
+                );
+                self.downloads.failed(FailedDownload {
+                    key: Some(DownloadKey::Message(chat_id, message_id)),
+                    rel_path: format!("chat_{chat_id}/{message_id}"),
+                    error: "Media has expired".to_owned(),
+                    attempts: 0,
+                });
+            }
+            return None;
+        };
+
+        let media_rel_path = format!("chat_{chat_id}/{message_id}.{media_ext}");
+        let absolute_path = self.media_path.join(&media_rel_path);
+        if absolute_path.exists() {
+            return Some(DownloadedMedia {
+                media_rel_path,
+                thumbnail_rel_path: None,
+            });
+        }
+        if !is_new {
+            return None; // Download of the new message failed, and was recorded then
+        }
+        log::info!(
+            "Message {message_id} in chat {chat_id} has self-destructing media (TTL {ttl_seconds}s), downloading it now"
+        );
+        let part_path = part_path(&absolute_path);
+        let mut error = String::new();
+        for attempt in 1..=EPHEMERAL_DOWNLOAD_ATTEMPTS {
+            METRICS.media_downloads_started.fetch_add(1, Ordering::Relaxed);
+            let result = async {
+                fs::create_dir_all(absolute_path.parent().unwrap())?;
+                self.client.download_media(&media_dl, &part_path).await?;
+                fs::rename(&part_path, &absolute_path).context("Failed to move downloaded file")
+            };
+            match result.await {
+                Ok(()) => {
+                    log::info!("Successfully downloaded {media_rel_path}");
+                    METRICS.media_downloads_succeeded.fetch_add(1, Ordering::Relaxed);
+                    if let Ok(metadata) = absolute_path.metadata() {
+                        METRICS
+                            .media_downloaded_bytes
+                            .fetch_add(metadata.len(), Ordering::Relaxed);
+                    }
+                    return Some(DownloadedMedia {
+                        media_rel_path,
+                        thumbnail_rel_path: None,
+                    });
                 }
+                Err(e) => {
+                    log::warn!("Attempt {attempt} to download {media_rel_path} failed: {e:#}");
+                    METRICS.media_downloads_failed.fetch_add(1, Ordering::Relaxed);
+                    let _ = fs::remove_file(&part_path);
+                    error = format!("{e:#}");
+                }
+            }
+            if attempt < EPHEMERAL_DOWNLOAD_ATTEMPTS {
+                tokio::time::sleep(EPHEMERAL_RETRY_DELAY * attempt).await;
             }
+        }
 
-            Ok::<_, anyhow::Error>(())
-        })
-    };
-    let spawned = Arc::new(Mutex::new(Some(spawned)));
+        log::error!("Self-destructing media {media_rel_path} is lost: {error}");
+        // Saved along with the message, see [Self::sync_downloads]
+        self.downloads.failed(FailedDownload {
+            key: Some(DownloadKey::Message(chat_id, message_id)),
+            rel_path: media_rel_path,
+            error,
+            attempts: EPHEMERAL_DOWNLOAD_ATTEMPTS,
+        });
+        None
+    }
 
-    {
-        let spawned = spawned.clone();
-        ctrlc::set_handler(move || {
-            log::info!("Received Ctrl+C, stopping...");
-            interrupted.store(true, std::sync::atomic::Ordering::SeqCst);
-            let spawned_lock = spawned.lock().unwrap();
-            if let Some(ref spawned) = *spawned_lock {
-                spawned.abort();
-            }
-        })?;
-    }
-
-    // Wait for the spawned task to finish
-    // Have to resort to busy loop here :(
-    let awaited = loop {
-        let finished = {
-            let mut spawned_lock = spawned.lock().unwrap();
-
-            // Take out the spawned task if it's finished
-            if spawned_lock.as_ref().is_some_and(|s| s.is_finished()) {
-                spawned_lock.take()
-            } else {
-                None
+    /// Fetches metadata of the sticker's set, the first time the set is seen.
+    /// Sets that fail to be fetched (e.g. deleted ones) are only requested once per session.
+    async fn fetch_sticker_set(
+        &mut self,
+        raw_message: &tl::enums::Message,
+    ) -> Result<Option<tl::types::StickerSet>> {
+        let tl::enums::Message::Message(message) = raw_message else {
+            return Ok(None);
+        };
+        let Some((_, attribute)) = message.media.as_ref().and_then(sticker) else {
+            return Ok(None);
+        };
+        let Some(set_id) = sticker_set_id(&attribute.stickerset) else {
+            return Ok(None);
+        };
+        if self.failed_sticker_sets.contains(&set_id) || self.database.has_sticker_set(set_id)? {
+            return Ok(None);
+        }
+        let result = self
+            .client
+            .invoke(&tl::functions::messages::GetStickerSet {
+                stickerset: attribute.stickerset.clone(),
+                hash: 0,
+            })
+            .await;
+        if result.is_err() {
+            self.failed_sticker_sets.insert(set_id);
+        }
+        let tl::enums::messages::StickerSet::Set(set) =
+            result.with_context(|| format!("Failed to fetch sticker set {set_id}"))?
+        else {
+            return Ok(None); // Not modified, can't happen with zero hash
+        };
+        let tl::enums::StickerSet::Set(set) = set.set;
+        log::info!("New sticker set {set_id}: {}", set.title);
+        Ok(Some(set))
+    }
+
+    /// Fetches users and channels the message refers to as its sender, forward origin or inline bot,
+    /// when the update didn't include them, so that they can be resolved from the chat cache.
+    /// Each peer is only requested once per session, whether it could be resolved or not.
+    async fn fetch_missing_peers(
+        &mut self,
+        raw_message: &tl::enums::Message,
+    ) -> Result<Option<Arc<ChatMap>>> {
+        // Without access hashes, peers can only be referred to through the message mentioning them
+        let peer = match raw_message.chat_id().and_then(|id| self.database.chat(id)) {
+            Some(chat) => chat.pack().to_input_peer(),
+            None => return Ok(None),
+        };
+        let missing: Vec<_> = attribution::referenced_peers(raw_message)
+            .into_iter()
+            .filter(|peer| {
+                peer.chat_id().is_some_and(|id| {
+                    self.database.chat(id).is_none() && self.requested_peers.insert(id)
+                })
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        let msg_id = raw_message.id();
+        let mut users = Vec::new();
+        let mut channels = Vec::new();
+        for missing_peer in missing {
+            match missing_peer {
+                tl::enums::Peer::User(user) => users.push(
+                    tl::types::InputUserFromMessage {
+                        peer: peer.clone(),
+                        msg_id,
+                        user_id: user.user_id,
+                    }
+                    .into(),
+                ),
+                tl::enums::Peer::Channel(channel) => channels.push(
+                    tl::types::InputChannelFromMessage {
+                        peer: peer.clone(),
+                        msg_id,
+                        channel_id: channel.channel_id,
+                    }
+                    .into(),
+                ),
+                // Basic groups don't send messages on their own
+                tl::enums::Peer::Chat(_) => {}
             }
+        }
+        log::debug!(
+            "Fetching {} users and {} channels missing from the update",
+            users.len(),
+            channels.len()
+        );
+
+        let users = if users.is_empty() {
+            Vec::new()
+        } else {
+            self.client
+                .invoke(&tl::functions::users::GetUsers { id: users })
+                .await
+                .context("Failed to fetch users")?
         };
+        let chats = if channels.is_empty() {
+            Vec::new()
+        } else {
+            match self
+                .client
+                .invoke(&tl::functions::channels::GetChannels { id: channels })
+                .await
+                .context("Failed to fetch channels")?
+            {
+                tl::enums::messages::Chats::Chats(chats) => chats.chats,
+                tl::enums::messages::Chats::Slice(chats) => chats.chats,
+            }
+        };
+        Ok(Some(ChatMap::new(users, chats)))
+    }
 
-        if let Some(finished) = finished {
-            break finished.await;
+    /// Archives a story with its media. Skipped stories, which the update only has the ID of,
+    /// have been fetched in full already, see [Self::prefetch]. Stories are never marked as viewed.
+    fn save_story(&mut self, peer: tl::enums::Peer, story: tl::enums::StoryItem) -> Result<()> {
+        let peer_id = peer.chat_id().unwrap();
+        match story {
+            tl::enums::StoryItem::Item(story) => {
+                if !self.database.story_changed(peer_id, &story)? {
+                    log::debug!("Story {} of {peer_id} is unchanged", story.id);
+                    return Ok(());
+                }
+                log::info!("New story {} of {peer_id}", story.id);
+                let media = self.download_story_media(peer_id, &story)?;
+                self.database.save_story(peer_id, &story, media)
+            }
+            tl::enums::StoryItem::Deleted(deleted) => {
+                log::info!("Story {} of {peer_id} deleted", deleted.id);
+                self.database.save_story_deleted(peer_id, deleted.id)
+            }
+            tl::enums::StoryItem::Skipped(skipped) => {
+                log::warn!("Story {} of {peer_id} is unavailable", skipped.id);
+                Ok(())
+            }
         }
+    }
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
-    };
+    /// Starts downloads of story media. Path has the photo or document ID in it,
+    /// so that media replaced by an edit doesn't overwrite the previous version.
+    fn download_story_media(
+        &mut self,
+        peer_id: i64,
+        story: &tl::types::StoryItem,
+    ) -> Result<Option<DownloadedMedia>> {
+        let version = media_file_id(&story.media).map_or(String::new(), |id| format!("_{id}"));
+        let key = DownloadKey::Story(peer_id, story.id);
+        let media = download_raw_media(
+            &self.client,
+            &self.media_path,
+            &format!("chat_{peer_id}/story_{}{version}", story.id),
+            &story.media,
+            &self.downloads,
+            Some(key),
+            self.tgs_to_json,
+        )?;
+        if self.downloads.is_pending(key) {
+            self.database
+                .save_pending_story_download(&PendingStoryDownload {
+                    peer_id,
+                    story_id: story.id,
+                    serialized: tl::enums::StoryItem::from(story.clone()).to_bytes(),
+                })?;
+        }
+        Ok(media)
+    }
 
-    client.session().save_to_file(&session_file)?;
-    drop(client);
+    /// Fetches a story by its ID. Unlike reading stories, this doesn't mark it as viewed.
+    async fn fetch_story(
+        &self,
+        peer_id: i64,
+        story_id: i32,
+    ) -> Result<Option<tl::enums::StoryItem>> {
+        let Some(chat) = self.database.chat(peer_id) else {
+            return Ok(None); // Can't refer to an unknown peer
+        };
+        let tl::enums::stories::Stories::Stories(stories) = self
+            .client
+            .invoke(&tl::functions::stories::GetStoriesById {
+                peer: chat.pack().to_input_peer(),
+                id: vec![story_id],
+            })
+            .await
+            .context("Failed to fetch story")?;
+        Ok(stories.stories.into_iter().next())
+    }
 
-    match awaited {
-        Err(e) if e.is_cancelled() => {
-            // NOOP
-            Ok(())
+    /// Downloads profile photos of chats whose photo has changed, keeping the old versions
+    fn download_avatars(&mut self, chats: &ChatMap) -> Result<()> {
+        for chat in chats.iter_chats() {
+            let chat_id = chat.id();
+            let Some(photo_id) = chat_photo_id(chat) else {
+                continue; // Unknown, which doesn't mean it was removed
+            };
+            if !self.database.avatar_changed(chat_id, photo_id) {
+                continue;
+            }
+            log::info!("Profile photo of chat {chat_id} changed to {photo_id:?}");
+            let rel_path = match (photo_id, chat.photo_downloadable(true)) {
+                (Some(photo_id), Some(photo)) => {
+                    let rel_path = format!("{AVATARS_SUBDIR}/{chat_id}/{photo_id}.jpg");
+                    download_media_in_background(
+                        &self.client,
+                        &self.media_path,
+                        DownloadableWrapper::new(photo),
+                        &rel_path,
+                        &self.downloads,
+                        None,
+                        false,
+                    )?;
+                    Some(rel_path)
+                }
+                _ => None,
+            };
+            self.database.save_avatar(chat_id, photo_id, rel_path.as_deref())?;
         }
-        etc => etc?,
+        Ok(())
+    }
+
+    /// Removes finished downloads from the database, and records failed ones
+    fn sync_downloads(&mut self) -> Result<()> {
+        for key in self.downloads.take_completed() {
+            match key {
+                DownloadKey::Message(chat_id, message_id) => {
+                    self.database.delete_pending_download(chat_id, message_id)?;
+                }
+                DownloadKey::Story(peer_id, story_id) => {
+                    self.database
+                        .delete_pending_story_download(peer_id, story_id)?;
+                }
+            }
+        }
+        for failed in self.downloads.take_failed() {
+            self.database.save_failed_download(&failed)?;
+        }
+        Ok(())
     }
 }
 
@@ -252,30 +1309,204 @@ async fn download_media_raw(
     media_path: &Path,
     raw_message: &tl::enums::Message,
     client: &Client,
+    downloads: &Downloads,
+    tgs_to_json: bool,
 ) -> Result<Option<DownloadedMedia>> {
     use tl::enums::*;
 
     let msg_id = raw_message.id();
 
-    let Message::Message(raw_message) = raw_message else {
-        return Ok(None); // Only Messages can have media
+    match raw_message {
+        Message::Message(raw_message) => {
+            let Some(ref raw_media) = raw_message.media else {
+                return Ok(None); // No media in this message
+            };
+            let chat_id = raw_message.chat_id().unwrap();
+            download_raw_media(
+                client,
+                media_path,
+                &format!("chat_{chat_id}/{msg_id}"),
+                raw_media,
+                downloads,
+                Some(DownloadKey::Message(chat_id, msg_id)),
+                tgs_to_json,
+            )
+        }
+        // New chat photo
+        Message::Service(raw_message) => match raw_message.action {
+            MessageAction::ChatEditPhoto(ref action) => {
+                let chat_id = raw_message.chat_id().unwrap();
+                let photo = types::Photo::from_raw(action.photo.clone());
+                start_downloads(
+                    client,
+                    media_path,
+                    &format!("chat_{chat_id}/{msg_id}"),
+                    ("jpg".to_owned(), DownloadableWrapper::new(photo), None),
+                    downloads,
+                    Some(DownloadKey::Message(chat_id, msg_id)),
+                )
+                .map(Some)
+            }
+            _ => Ok(None),
+        },
+        Message::Empty(_) => Ok(None),
+    }
+}
+
+/// Downloads media as `<base_rel_path>.<ext>`, or writes its local rendering if there's nothing to download
+fn download_raw_media(
+    client: &Client,
+    media_path: &Path,
+    base_rel_path: &str,
+    raw_media: &tl::enums::MessageMedia,
+    downloads: &Downloads,
+    key: Option<DownloadKey>,
+    tgs_to_json: bool,
+) -> Result<Option<DownloadedMedia>> {
+    // Nothing to download, write local rendering instead
+    if let Some((ext, content)) = render::render(raw_media) {
+        let media_rel_path = format!("{base_rel_path}.{ext}");
+        let absolute_path = media_path.join(&media_rel_path);
+        fs::create_dir_all(absolute_path.parent().unwrap())?;
+        fs::write(&absolute_path, content)
+            .with_context(|| format!("Failed to write {media_rel_path}"))?;
+        log::info!("Saved {} as {media_rel_path}", describe_media(raw_media));
+        return Ok(Some(DownloadedMedia {
+            media_rel_path,
+            thumbnail_rel_path: None,
+        }));
+    }
+    let downloadables = match raw_media {
+        tl::enums::MessageMedia::WebPage(webpage) => webpage_downloadables(webpage),
+        _ => Media::from_raw(raw_media.clone()).and_then(media_downloadables),
     };
-    let Some(ref raw_media) = raw_message.media else {
-        return Ok(None); // No media in this message
+    let Some(downloadables) = downloadables else {
+        return Ok(None); // Nothing to download
     };
-    let Some(media) = Media::from_raw(raw_media.clone()) else {
-        return Ok(None); // No media in this message
+    if let Some((document, attribute)) = sticker(raw_media) {
+        let set =
+            sticker_set_id(&attribute.stickerset).map_or("none".to_owned(), |id| id.to_string());
+        return download_sticker(
+            client,
+            media_path,
+            &format!("{STICKERS_SUBDIR}/{set}/{}", document.id),
+            downloadables,
+            downloads,
+            key,
+            tgs_to_json,
+        )
+        .map(Some);
+    }
+    start_downloads(
+        client,
+        media_path,
+        base_rel_path,
+        downloadables,
+        downloads,
+        key,
+    )
+    .map(Some)
+}
+
+/// Starts downloads of media as `<base_rel_path>.<ext>`, and of its thumbnail, if any
+fn start_downloads(
+    client: &Client,
+    media_path: &Path,
+    base_rel_path: &str,
+    (media_ext, media_dl, thumb_dl): Downloadables,
+    downloads: &Downloads,
+    key: Option<DownloadKey>,
+) -> Result<DownloadedMedia> {
+    let media_rel_path = format!("{base_rel_path}.{media_ext}");
+    download_media_in_background(
+        client,
+        media_path,
+        media_dl,
+        &media_rel_path,
+        downloads,
+        key,
+        false,
+    )?;
+
+    let thumbnail_rel_path = if let Some(thumb_dl) = thumb_dl {
+        let thumb_rel_path = format!("{media_rel_path}_thumb.jpg");
+        download_media_in_background(
+            client,
+            media_path,
+            thumb_dl,
+            &thumb_rel_path,
+            downloads,
+            key,
+            false,
+        )?;
+        Some(thumb_rel_path)
+    } else {
+        None
     };
 
-    let chat_id = raw_message.chat_id().unwrap();
+    Ok(DownloadedMedia {
+        media_rel_path,
+        thumbnail_rel_path,
+    })
+}
+
+/// Starts downloads of sticker into the shared store, unless it's already there or being downloaded
+fn download_sticker(
+    client: &Client,
+    media_path: &Path,
+    base_rel_path: &str,
+    (media_ext, media_dl, thumb_dl): Downloadables,
+    downloads: &Downloads,
+    key: Option<DownloadKey>,
+    tgs_to_json: bool,
+) -> Result<DownloadedMedia> {
+    let stored =
+        |rel_path: &str| media_path.join(rel_path).exists() || downloads.is_downloading(rel_path);
 
-    // Determine file extension based on media type
-    let (media_ext, media_dl, thumb_dl): (
-        String,
-        DownloadableWrapper,
-        Option<DownloadableWrapper>,
-    ) = match media {
-        Media::Photo(p) => ("jpg".to_owned(), DownloadableWrapper::new(p), None),
+    let media_rel_path = format!("{base_rel_path}.{media_ext}");
+    if !stored(&media_rel_path) {
+        download_media_in_background(
+            client,
+            media_path,
+            media_dl,
+            &media_rel_path,
+            downloads,
+            key,
+            tgs_to_json && media_ext == "tgs",
+        )?;
+    }
+
+    let thumbnail_rel_path = thumb_dl
+        .map(|thumb_dl| {
+            let thumb_rel_path = format!("{media_rel_path}_thumb.jpg");
+            if !stored(&thumb_rel_path) {
+                download_media_in_background(
+                    client,
+                    media_path,
+                    thumb_dl,
+                    &thumb_rel_path,
+                    downloads,
+                    key,
+                    false,
+                )?;
+            }
+            anyhow::Ok(thumb_rel_path)
+        })
+        .transpose()?;
+
+    Ok(DownloadedMedia {
+        media_rel_path,
+        thumbnail_rel_path,
+    })
+}
+
+/// File extension, media and optionally thumbnail to download
+type Downloadables = (String, DownloadableWrapper, Option<DownloadableWrapper>);
+
+/// Determine file extension based on media type
+fn media_downloadables(media: Media) -> Option<Downloadables> {
+    match media {
+        Media::Photo(p) => Some(("jpg".to_owned(), DownloadableWrapper::new(p), None)),
         Media::Sticker(s) => {
             let ext = if s.is_animated() {
                 "tgs"
@@ -283,11 +1514,11 @@ async fn download_media_raw(
                 guess_extension(s.document.mime_type(), "webp")
             };
             let thumbs = s.document.thumbs();
-            (
+            Some((
                 ext.to_owned(),
                 DownloadableWrapper::new(s.document),
                 pick_largest(thumbs).map(DownloadableWrapper::new),
-            )
+            ))
         }
         Media::Document(doc) => {
             let name = doc.name();
@@ -302,60 +1533,79 @@ async fn download_media_raw(
                 guess_extension(doc.mime_type(), "bin")
             };
             let thumbs = doc.thumbs();
-            (
+            Some((
                 ext.to_owned(),
                 DownloadableWrapper::new(doc),
                 pick_largest(thumbs).map(DownloadableWrapper::new),
-            )
+            ))
         }
-        Media::Contact(_) => (
-            "vcf".to_owned(),
-            DownloadableWrapper::new(NotDownloadable),
-            None,
-        ),
-        Media::Poll(_)
+        // Rendered locally, unless there's nothing to render, e.g. an empty location
+        Media::Contact(_)
+        | Media::Poll(_)
         | Media::Geo(_)
         | Media::GeoLive(_)
         | Media::Venue(_)
-        | Media::Dice(_)
-        | Media::WebPage(_) => {
+        // Downloaded from the raw web page
+        | Media::WebPage(_)
+        | Media::Dice(_) => {
             // Not downloadable
-            return Ok(None);
+            None
         }
         media => unreachable!("Unexpected media type: {:?}", media),
-    };
-    let file_name = format!("{msg_id}.{media_ext}");
-
-    // Get chat info for the filename
-    let chat_name = format!("chat_{chat_id}");
-
-    let media_rel_path = {
-        let media_rel_path = format!("{chat_name}/{file_name}");
-        download_media_in_background(client, media_path, media_dl, &media_rel_path)?;
-        media_rel_path
-    };
+    }
+}
 
-    let thumbnail_rel_path = if let Some(thumb_dl) = thumb_dl {
-        let thumb_file_name = format!("{file_name}_thumb.jpg");
-        let thumb_rel_path = format!("{chat_name}/{thumb_file_name}");
-        download_media_in_background(client, media_path, thumb_dl, &thumb_rel_path)?;
-        Some(thumb_rel_path)
-    } else {
-        None
+/// Document of a link preview (e.g. a video) with its preview image as thumbnail,
+/// or just the preview image
+fn webpage_downloadables(media: &tl::types::MessageMediaWebPage) -> Option<Downloadables> {
+    let tl::enums::WebPage::Page(ref webpage) = media.webpage else {
+        return None; // Preview isn't ready yet, it arrives with an edit
     };
+    let photo = webpage
+        .photo
+        .clone()
+        .filter(|photo| matches!(photo, tl::enums::Photo::Photo(_)))
+        .map(types::Photo::from_raw);
+    match webpage.document {
+        Some(tl::enums::Document::Document(ref doc)) => {
+            let doc = RawDocument(doc.clone());
+            let ext = doc
+                .extension()
+                .unwrap_or_else(|| guess_extension(Some(&doc.0.mime_type), "bin"))
+                .to_owned();
+            Some((
+                ext,
+                DownloadableWrapper::new(doc),
+                photo.map(DownloadableWrapper::new),
+            ))
+        }
+        _ => photo.map(|photo| ("jpg".to_owned(), DownloadableWrapper::new(photo), None)),
+    }
+}
 
-    Ok(Some(DownloadedMedia {
-        media_rel_path,
-        thumbnail_rel_path,
-    }))
+/// Temporary file media is downloaded into, before it's moved into place once complete
+fn part_path(absolute_path: &Path) -> PathBuf {
+    let mut part_path = absolute_path.to_owned().into_os_string();
+    part_path.push(".part");
+    PathBuf::from(part_path)
 }
 
+/// Downloads into a temporary file first, so that existing file is always complete.
+/// Files already being downloaded are skipped, their download would share the temporary file.
+/// With `tgs_to_json`, `.tgs` sticker is also decompressed into Lottie JSON next to it.
 fn download_media_in_background(
     client: &Client,
     media_root_path: &Path,
     media_dl: DownloadableWrapper,
     rel_path: &str,
+    downloads: &Downloads,
+    key: Option<DownloadKey>,
+    tgs_to_json: bool,
 ) -> Result<()> {
+    if downloads.is_downloading(rel_path) {
+        log::debug!("Media is already being downloaded to {rel_path}");
+        return Ok(());
+    }
     let absolute_path = media_root_path.join(rel_path);
     fs::create_dir_all(absolute_path.parent().unwrap())?;
 
@@ -364,49 +1614,77 @@ fn download_media_in_background(
         // TODO: Skip if check sums match
         log::info!("File already exists, overwriting: {rel_path}");
     }
+    let part_path = part_path(&absolute_path);
 
     let client = client.clone();
     let rel_path = rel_path.to_owned();
-    tokio::spawn(async move {
-        match client.download_media(&media_dl, &absolute_path).await {
-            Ok(_) => log::info!("Successfully downloaded {rel_path}"),
-            Err(e) => log::error!("Failed to download media {rel_path}: {}", e),
+    METRICS.media_downloads_started.fetch_add(1, Ordering::Relaxed);
+    METRICS.pending_downloads.fetch_add(1, Ordering::Relaxed);
+    downloads.started(&rel_path, key);
+    let tracker = downloads.tracker();
+    let downloads = downloads.clone();
+    tracker.spawn(async move {
+        let result = match client.download_media(&media_dl, &part_path).await {
+            Ok(_) => {
+                fs::rename(&part_path, &absolute_path).context("Failed to move downloaded file")
+            }
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(()) => {
+                log::info!("Successfully downloaded {rel_path}");
+                METRICS.media_downloads_succeeded.fetch_add(1, Ordering::Relaxed);
+                if let Ok(metadata) = absolute_path.metadata() {
+                    METRICS
+                        .media_downloaded_bytes
+                        .fetch_add(metadata.len(), Ordering::Relaxed);
+                }
+                if let Some(Err(e)) = tgs_to_json.then(|| render::tgs_to_json(&absolute_path)) {
+                    log::warn!("Failed to decompress sticker {rel_path}: {e:#}");
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to download media {rel_path}: {e:#}");
+                METRICS.media_downloads_failed.fetch_add(1, Ordering::Relaxed);
+                let _ = fs::remove_file(&part_path);
+                downloads.failed(FailedDownload {
+                    key,
+                    rel_path: rel_path.clone(),
+                    error: format!("{e:#}"),
+                    attempts: 1,
+                });
+            }
         }
+        METRICS.pending_downloads.fetch_sub(1, Ordering::Relaxed);
+        downloads.finished(&rel_path);
     });
 
     Ok(())
 }
 
-fn to_pretty_summary(msg: &tl::enums::Message, chat_map: &HashMap<i64, types::Chat>) -> String {
+/// Short update type name, used as a metrics label
+fn update_type_name(update: &tl::enums::Update) -> &'static str {
+    match update {
+        tl::enums::Update::NewMessage(_) => "new_message",
+        tl::enums::Update::EditMessage(_) => "edit_message",
+        tl::enums::Update::DeleteMessages(_) => "delete_messages",
+        tl::enums::Update::NewChannelMessage(_) => "new_channel_message",
+        tl::enums::Update::EditChannelMessage(_) => "edit_channel_message",
+        tl::enums::Update::DeleteChannelMessages(_) => "delete_channel_messages",
+        tl::enums::Update::MessagePoll(_) => "message_poll",
+        tl::enums::Update::Story(_) => "story",
+        tl::enums::Update::ChannelAvailableMessages(_) => "channel_available_messages",
+        _ => "other",
+    }
+}
+
+fn to_pretty_summary(msg: &tl::enums::Message, database: &db::Database) -> String {
     // Extract chat ID
     let chat_id = match msg.chat_id() {
         Some(id) => id,
         None => return "[Unknown chat]: <no message data>".to_string(),
     };
 
-    /// Helper function to describe media type
-    fn describe_media(media: &tl::enums::MessageMedia) -> &'static str {
-        match media {
-            tl::enums::MessageMedia::Photo(_) => "photo",
-            tl::enums::MessageMedia::Document(_) => "document",
-            tl::enums::MessageMedia::Geo(_) => "geo",
-            tl::enums::MessageMedia::Contact(_) => "contact",
-            tl::enums::MessageMedia::Unsupported => "unsupported",
-            tl::enums::MessageMedia::WebPage(_) => "webpage",
-            tl::enums::MessageMedia::Venue(_) => "venue",
-            tl::enums::MessageMedia::Game(_) => "game",
-            tl::enums::MessageMedia::Invoice(_) => "invoice",
-            tl::enums::MessageMedia::GeoLive(_) => "geo live",
-            tl::enums::MessageMedia::Poll(_) => "poll",
-            tl::enums::MessageMedia::Dice(_) => "dice",
-            tl::enums::MessageMedia::Empty => "empty",
-            tl::enums::MessageMedia::Story(_) => "story",
-            tl::enums::MessageMedia::Giveaway(_) => "giveaway",
-            tl::enums::MessageMedia::GiveawayResults(_) => "giveaway results",
-            tl::enums::MessageMedia::PaidMedia(_) => "paid media",
-        }
-    }
-
     // Get message text or description
     let message_text = match msg {
         tl::enums::Message::Message(m) if !m.message.is_empty() => m.message.clone(),
@@ -414,12 +1692,18 @@ fn to_pretty_summary(msg: &tl::enums::Message, chat_map: &HashMap<i64, types::Ch
             Some(media) => format!("<{}>", describe_media(media)),
             None => "<empty message>".to_owned(),
         },
-        tl::enums::Message::Service(m) => format!("<service: {:?}>", m.action),
+        tl::enums::Message::Service(m) => format!("<service: {}>", actions::name(m)),
         tl::enums::Message::Empty(_) => "<empty>".to_owned(),
     };
 
-    let chat = chat_map.get(&chat_id);
+    let chat = database.chat(chat_id);
     let chat_name = chat.and_then(|c| c.name()).unwrap_or("<no name>");
+    // In private chats, sender is usually the chat itself
+    let sender = attribution::decode(msg, database)
+        .and_then(|a| a.summary())
+        .filter(|sender| sender != chat_name)
+        .map(|sender| format!(" {sender}"))
+        .unwrap_or_default();
     let mut lines = message_text.trim().lines();
     let mut first_line = lines
         .next()
@@ -430,7 +1714,7 @@ fn to_pretty_summary(msg: &tl::enums::Message, chat_map: &HashMap<i64, types::Ch
     }
 
     // Format the summary for text messages
-    format!("{chat_name} (#{chat_id}): {first_line}")
+    format!("{chat_name} (#{chat_id}){sender}: {first_line}")
 }
 
 fn guess_extension<'a>(mime_type_opt: Option<&'a str>, default: &'a str) -> &'a str {
@@ -0,0 +1,161 @@
+use crate::utils::unix_timestamp;
+use grammers_mtsender::{FixedReconnect, ReconnectionPolicy};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Global metrics registry, rendered in Prometheus text format on `/metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    updates_received: Mutex<BTreeMap<&'static str, u64>>,
+    pub events_saved: AtomicU64,
+    pub messages_deleted: AtomicU64,
+    pub media_downloads_started: AtomicU64,
+    pub media_downloads_succeeded: AtomicU64,
+    pub media_downloads_failed: AtomicU64,
+    pub media_downloaded_bytes: AtomicU64,
+    pub reconnects: AtomicU64,
+    pub server_retries: AtomicU64,
+    pub last_update_timestamp: AtomicI64,
+    pub pending_downloads: AtomicI64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            updates_received: Mutex::new(BTreeMap::new()),
+            events_saved: AtomicU64::new(0),
+            messages_deleted: AtomicU64::new(0),
+            media_downloads_started: AtomicU64::new(0),
+            media_downloads_succeeded: AtomicU64::new(0),
+            media_downloads_failed: AtomicU64::new(0),
+            media_downloaded_bytes: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            server_retries: AtomicU64::new(0),
+            last_update_timestamp: AtomicI64::new(0),
+            pending_downloads: AtomicI64::new(0),
+        }
+    }
+
+    pub fn update_received(&self, update_type: &'static str) {
+        *self
+            .updates_received
+            .lock()
+            .unwrap()
+            .entry(update_type)
+            .or_default() += 1;
+        self.last_update_timestamp.store(unix_timestamp(), Ordering::Relaxed);
+    }
+
+    /// Renders metrics in Prometheus text exposition format.
+    pub fn render(&self, db_file: &Path) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "updates_received_total",
+            "counter",
+            "Updates received from Telegram, by type",
+        );
+        for (update_type, count) in self.updates_received.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "tg_keeper_updates_received_total{{type=\"{update_type}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        let counters = [
+            (
+                "events_saved_total",
+                "Events saved to database",
+                &self.events_saved,
+            ),
+            (
+                "messages_deleted_total",
+                "Messages reported as deleted",
+                &self.messages_deleted,
+            ),
+            (
+                "media_downloads_started_total",
+                "Media downloads started",
+                &self.media_downloads_started,
+            ),
+            (
+                "media_downloads_succeeded_total",
+                "Media downloads succeeded",
+                &self.media_downloads_succeeded,
+            ),
+            (
+                "media_downloads_failed_total",
+                "Media downloads failed",
+                &self.media_downloads_failed,
+            ),
+            (
+                "media_downloaded_bytes_total",
+                "Bytes of media downloaded",
+                &self.media_downloaded_bytes,
+            ),
+            (
+                "reconnects_total",
+                "Reconnection attempts to Telegram",
+                &self.reconnects,
+            ),
+            (
+                "server_retries_total",
+                "Retries caused by temporary Telegram server errors",
+                &self.server_retries,
+            ),
+        ];
+        for (name, help, value) in counters {
+            write_header(&mut out, name, "counter", help);
+            writeln!(out, "tg_keeper_{name} {}", value.load(Ordering::Relaxed)).unwrap();
+        }
+
+        let db_size = [db_file.to_owned(), db_file.with_extension("sqlite-wal")]
+            .iter()
+            .filter_map(|p| p.metadata().ok())
+            .map(|m| m.len() as i64)
+            .sum::<i64>();
+        let gauges = [
+            (
+                "last_update_timestamp_seconds",
+                "Unix timestamp of the last update received",
+                self.last_update_timestamp.load(Ordering::Relaxed),
+            ),
+            ("database_size_bytes", "Size of the database file", db_size),
+            (
+                "pending_downloads",
+                "Media downloads currently in progress",
+                self.pending_downloads.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            write_header(&mut out, name, "gauge", help);
+            writeln!(out, "tg_keeper_{name} {value}").unwrap();
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP tg_keeper_{name} {help}").unwrap();
+    writeln!(out, "# TYPE tg_keeper_{name} {metric_type}").unwrap();
+}
+
+/// Reconnection policy that counts reconnection attempts
+pub struct CountingReconnect(pub FixedReconnect);
+
+impl ReconnectionPolicy for CountingReconnect {
+    fn should_retry(&self, attempts: usize) -> ControlFlow<(), Duration> {
+        METRICS.reconnects.fetch_add(1, Ordering::Relaxed);
+        log::warn!("Connection lost, reconnecting (attempt {attempts})");
+        self.0.should_retry(attempts)
+    }
+}
@@ -0,0 +1,456 @@
+use crate::db::Database;
+use crate::utils::*;
+use anyhow::{Context, Result, ensure};
+use config::Config as AppConfig;
+use grammers_client::grammers_tl_types as tl;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    Deleted,
+    Edited,
+}
+
+/// Notification about a change to an archived message.
+/// Original message is looked up in the archive, so its fields are unknown if it wasn't archived.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    /// Might be unknown for deleted messages, see `Database::save_messages_deleted`
+    pub chat_id: Option<i64>,
+    pub chat_name: Option<String>,
+    pub message_id: i32,
+    pub sender_id: Option<i64>,
+    pub sender_name: Option<String>,
+    pub original_text: Option<String>,
+    /// Only for edited messages
+    pub new_text: Option<String>,
+}
+
+impl Notification {
+    pub fn new(
+        kind: NotificationKind,
+        chat_id: Option<i64>,
+        message_id: i32,
+        original: Option<&tl::enums::Message>,
+        edited: Option<&tl::enums::Message>,
+        database: &Database,
+    ) -> Self {
+        let chat_id = chat_id.or_else(|| original.and_then(|m| m.chat_id()));
+        let sender_id = original.or(edited).and_then(|m| m.sender_id());
+        let name_of = |id: i64| database.chat(id).and_then(|c| c.name()).map(str::to_owned);
+        Notification {
+            kind,
+            chat_id,
+            chat_name: chat_id.and_then(name_of),
+            message_id,
+            sender_id,
+            sender_name: sender_id.and_then(name_of),
+            original_text: original.and_then(|m| m.text()).map(str::to_owned),
+            new_text: edited.and_then(|m| m.text()).map(str::to_owned),
+        }
+    }
+
+    fn title(&self) -> String {
+        let action = match self.kind {
+            NotificationKind::Deleted => "Message deleted",
+            NotificationKind::Edited => "Message edited",
+        };
+        match (&self.chat_name, &self.sender_name) {
+            (Some(chat), Some(sender)) if chat != sender => {
+                format!("{action}: {sender} in {chat}")
+            }
+            (_, Some(sender)) => format!("{action}: {sender}"),
+            (Some(chat), None) => format!("{action} in {chat}"),
+            (None, None) => action.to_owned(),
+        }
+    }
+
+    fn body(&self) -> String {
+        let original = self
+            .original_text
+            .as_deref()
+            .unwrap_or("<not archived or no text>");
+        match self.new_text {
+            Some(ref new_text) => format!("{original}\n→ {new_text}"),
+            None => original.to_owned(),
+        }
+    }
+}
+
+/// Notification target
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Desktop notification via `notify-send`
+pub struct NotifySend;
+
+impl Notifier for NotifySend {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let status = tokio::process::Command::new("notify-send")
+                .args(["--app-name", "tg-keeper"])
+                .arg(notification.title())
+                .arg(notification.body())
+                .status()
+                .await
+                .context("Failed to run notify-send")?;
+            ensure!(status.success(), "notify-send exited with {status}");
+            Ok(())
+        })
+    }
+}
+
+/// Local command, receiving notification as JSON on stdin.
+/// Command is not run through a shell.
+pub struct CommandHook {
+    pub command: Vec<String>,
+}
+
+impl Notifier for CommandHook {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let json = serde_json::to_vec(notification)?;
+            let mut child = tokio::process::Command::new(&self.command[0])
+                .args(&self.command[1..])
+                .stdin(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Failed to run {}", self.command[0]))?;
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(&json).await?;
+            drop(stdin);
+            let status = child.wait().await?;
+            ensure!(status.success(), "{} exited with {status}", self.command[0]);
+            Ok(())
+        })
+    }
+}
+
+/// HTTP webhook, receiving notification as JSON in POST request body
+pub struct Webhook {
+    pub client: reqwest::Client,
+    pub url: String,
+}
+
+impl Notifier for Webhook {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(notification)
+                .send()
+                .await
+                .context("Failed to send webhook request")?
+                .error_for_status()
+                .context("Webhook returned error")?;
+            Ok(())
+        })
+    }
+}
+
+//
+// Configuration
+//
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum NotifierConfig {
+    NotifySend {
+        #[serde(flatten)]
+        filter: Filter,
+    },
+    Command {
+        command: Vec<String>,
+        #[serde(flatten)]
+        filter: Filter,
+    },
+    Webhook {
+        url: String,
+        #[serde(flatten)]
+        filter: Filter,
+    },
+}
+
+#[derive(Deserialize)]
+struct Filter {
+    /// Notification kinds to send, all if not specified
+    events: Option<Vec<NotificationKind>>,
+    /// Only notify about these chats, if specified
+    chats: Option<Vec<i64>>,
+    /// Never notify about these chats
+    #[serde(default)]
+    exclude_chats: Vec<i64>,
+}
+
+impl Filter {
+    fn matches(&self, notification: &Notification) -> bool {
+        if self
+            .events
+            .as_ref()
+            .is_some_and(|events| !events.contains(&notification.kind))
+        {
+            return false;
+        }
+        match notification.chat_id {
+            Some(chat_id) => {
+                !self.exclude_chats.contains(&chat_id)
+                    && self.chats.as_ref().is_none_or(|c| c.contains(&chat_id))
+            }
+            // Chat is unknown, only notify if not limited to specific chats
+            None => self.chats.is_none(),
+        }
+    }
+}
+
+/// All configured notification targets
+#[derive(Default)]
+pub struct Notifiers {
+    targets: Vec<(Filter, Arc<dyn Notifier>)>,
+}
+
+impl Notifiers {
+    pub fn from_config(settings: &AppConfig) -> Result<Self> {
+        let configs: Vec<NotifierConfig> = match settings.get("notifiers") {
+            Ok(configs) => configs,
+            Err(config::ConfigError::NotFound(_)) => return Ok(Self::default()),
+            Err(e) => return Err(e).context("Invalid notifiers config"),
+        };
+        let mut targets: Vec<(Filter, Arc<dyn Notifier>)> = Vec::with_capacity(configs.len());
+        for config in configs {
+            match config {
+                NotifierConfig::NotifySend { filter } => {
+                    targets.push((filter, Arc::new(NotifySend)))
+                }
+                NotifierConfig::Command { command, filter } => {
+                    ensure!(!command.is_empty(), "Notifier command must not be empty");
+                    targets.push((filter, Arc::new(CommandHook { command })))
+                }
+                NotifierConfig::Webhook { url, filter } => {
+                    let client = reqwest::Client::new();
+                    targets.push((filter, Arc::new(Webhook { client, url })))
+                }
+            }
+        }
+        log::info!("Configured {} notifier(s)", targets.len());
+        Ok(Notifiers { targets })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Sends notification to all matching targets in background
+    pub fn dispatch(&self, notification: Notification) {
+        let notification = Arc::new(notification);
+        for (filter, notifier) in &self.targets {
+            if !filter.matches(&notification) {
+                continue;
+            }
+            let notifier = notifier.clone();
+            let notification = notification.clone();
+            tokio::spawn(async move {
+                if let Err(e) = notifier.notify(&notification).await {
+                    log::error!("Failed to send notification: {e:#}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn notification(kind: NotificationKind, chat_id: Option<i64>) -> Notification {
+        Notification {
+            kind,
+            chat_id,
+            chat_name: Some("Chat".to_owned()),
+            message_id: 42,
+            sender_id: Some(7),
+            sender_name: Some("Sender".to_owned()),
+            original_text: Some("before".to_owned()),
+            new_text: Some("after".to_owned()),
+        }
+    }
+
+    fn filter(events: Option<Vec<NotificationKind>>, chats: Option<Vec<i64>>) -> Filter {
+        Filter {
+            events,
+            chats,
+            exclude_chats: vec![3],
+        }
+    }
+
+    #[test]
+    fn filter_matches() {
+        let edited = |chat_id| notification(NotificationKind::Edited, chat_id);
+
+        let all = filter(None, None);
+        assert!(all.matches(&edited(Some(1))));
+        assert!(all.matches(&edited(None)));
+        assert!(!all.matches(&edited(Some(3))));
+
+        let deleted_only = filter(Some(vec![NotificationKind::Deleted]), None);
+        assert!(!deleted_only.matches(&edited(Some(1))));
+        assert!(deleted_only.matches(&notification(NotificationKind::Deleted, Some(1))));
+
+        let some_chats = filter(None, Some(vec![1, 3]));
+        assert!(some_chats.matches(&edited(Some(1))));
+        assert!(!some_chats.matches(&edited(Some(2))));
+        assert!(!some_chats.matches(&edited(Some(3))));
+        assert!(!some_chats.matches(&edited(None)));
+    }
+
+    #[test]
+    fn channel_deletion() {
+        let db_path = std::env::temp_dir().join(format!(
+            "tg-keeper-notify-test-{}.sqlite",
+            std::process::id()
+        ));
+        let mut database = Database::new(&db_path, None).unwrap();
+        let in_channel = |channel_id| {
+            tl::enums::Message::Empty(tl::types::MessageEmpty {
+                id: 42,
+                peer_id: Some(tl::enums::Peer::Channel(tl::types::PeerChannel {
+                    channel_id,
+                })),
+            })
+        };
+        database
+            .save_message(&in_channel(100), false, None)
+            .unwrap();
+
+        // Channels number their messages on their own, so message 42 of another channel isn't it
+        database.save_messages_deleted(Some(200), &[42]).unwrap();
+        let original = database.find_latest_message(Some(200), 42).unwrap();
+        assert!(original.is_none());
+        let deleted = Notification::new(
+            NotificationKind::Deleted,
+            Some(200),
+            42,
+            original.as_ref(),
+            None,
+            &database,
+        );
+        assert_eq!(deleted.chat_id, Some(200));
+        assert!(filter(None, Some(vec![200])).matches(&deleted));
+        assert!(!filter(None, Some(vec![100])).matches(&deleted));
+
+        let original = database.find_latest_message(Some(100), 42).unwrap();
+        assert_eq!(original.and_then(|m| m.chat_id()), Some(100));
+
+        drop(database);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = db_path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn command_hook() {
+        let output_path =
+            std::env::temp_dir().join(format!("tg-keeper-notify-test-{}.json", std::process::id()));
+        let hook = CommandHook {
+            command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "cat > \"$0\"".to_owned(),
+                output_path.to_str().unwrap().to_owned(),
+            ],
+        };
+        hook.notify(&notification(NotificationKind::Edited, Some(1)))
+            .await
+            .unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&output_path).unwrap()).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+        assert_eq!(json["kind"], "edited");
+        assert_eq!(json["message_id"], 42);
+        assert_eq!(json["new_text"], "after");
+
+        let failing = CommandHook {
+            command: vec!["sh".to_owned(), "-c".to_owned(), "exit 3".to_owned()],
+        };
+        let notification = notification(NotificationKind::Deleted, None);
+        assert!(failing.notify(&notification).await.is_err());
+    }
+
+    /// Accepts a single request, responding with `status`, and returns the request
+    async fn serve_once(listener: tokio::net::TcpListener, status: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let content_length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if body.len() >= content_length {
+                    break;
+                }
+            }
+            assert!(n > 0, "Connection closed before the whole request was read");
+        }
+        let response =
+            format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn webhook() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "200 OK"));
+        let webhook = Webhook {
+            client: reqwest::Client::new(),
+            url,
+        };
+        webhook
+            .notify(&notification(NotificationKind::Deleted, Some(1)))
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook "));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["kind"], "deleted");
+        assert_eq!(json["chat_id"], 1);
+    }
+
+    #[tokio::test]
+    async fn webhook_error_status() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "500 Internal Server Error"));
+        let webhook = Webhook {
+            client: reqwest::Client::new(),
+            url,
+        };
+        let result = webhook
+            .notify(&notification(NotificationKind::Deleted, Some(1)))
+            .await;
+        server.await.unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use crate::utils::hex;
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use grammers_client::grammers_tl_types as tl;
+use serde_json::{Value, json};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Renders media that has nothing to download into a local file: contacts as vCard,
+/// locations as GeoJSON and polls as JSON.
+/// Returns file extension and content.
+pub fn render(media: &tl::enums::MessageMedia) -> Option<(&'static str, Vec<u8>)> {
+    match media {
+        tl::enums::MessageMedia::Contact(contact) => Some(("vcf", vcard(contact).into_bytes())),
+        tl::enums::MessageMedia::Geo(geo) => {
+            let feature = geo_feature(&geo.geo, json!({}))?;
+            Some(("geojson", to_json(&feature)))
+        }
+        tl::enums::MessageMedia::Venue(venue) => {
+            let properties = json!({
+                "title": venue.title,
+                "address": venue.address,
+                "provider": venue.provider,
+                "venue_id": venue.venue_id,
+                "venue_type": venue.venue_type,
+            });
+            let feature = geo_feature(&venue.geo, properties)?;
+            Some(("geojson", to_json(&feature)))
+        }
+        // Latest position only, the path is recorded in `live_locations` table
+        tl::enums::MessageMedia::GeoLive(live) => {
+            let properties = json!({
+                "live": true,
+                "period": live.period,
+                "heading": live.heading,
+            });
+            let feature = geo_feature(&live.geo, properties)?;
+            Some(("geojson", to_json(&feature)))
+        }
+        tl::enums::MessageMedia::Poll(poll) => Some(("json", to_json(&poll_json(poll)))),
+        _ => None,
+    }
+}
+
+fn to_json(value: &Value) -> Vec<u8> {
+    let mut json = serde_json::to_vec_pretty(value).unwrap();
+    json.push(b'\n');
+    json
+}
+
+/// vCard 3.0 from the contact fields, with extra properties of the embedded vCard, if any
+fn vcard(contact: &tl::types::MessageMediaContact) -> String {
+    let mut lines = vec![
+        "BEGIN:VCARD".to_owned(),
+        "VERSION:3.0".to_owned(),
+        format!(
+            "N:{};{};;;",
+            escape(&contact.last_name),
+            escape(&contact.first_name)
+        ),
+        format!(
+            "FN:{}",
+            escape(format!("{} {}", contact.first_name, contact.last_name).trim())
+        ),
+    ];
+    if !contact.phone_number.is_empty() {
+        lines.push(format!("TEL;TYPE=CELL:{}", escape(&contact.phone_number)));
+    }
+    if contact.user_id != 0 {
+        lines.push(format!("X-TELEGRAM-ID:{}", contact.user_id));
+    }
+    // Embedded vCard is whatever the sender's app exported, its properties are kept as is
+    for line in contact.vcard.lines() {
+        let name = line
+            .split([':', ';'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if !line.is_empty() && !matches!(name.as_str(), "BEGIN" | "END" | "VERSION" | "N" | "FN") {
+            lines.push(line.to_owned());
+        }
+    }
+    lines.push("END:VCARD".to_owned());
+    lines.join("\r\n") + "\r\n"
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+}
+
+/// GeoJSON `Feature` of the point, if it's not empty
+fn geo_feature(geo: &tl::enums::GeoPoint, mut properties: Value) -> Option<Value> {
+    let tl::enums::GeoPoint::Point(point) = geo else {
+        return None;
+    };
+    if let Some(accuracy_radius) = point.accuracy_radius {
+        properties["accuracy_radius"] = accuracy_radius.into();
+    }
+    Some(json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Point",
+            "coordinates": [point.long, point.lat],
+        },
+        "properties": properties,
+    }))
+}
+
+/// Poll question and options, with vote counts if they're known
+pub fn poll_json(media: &tl::types::MessageMediaPoll) -> Value {
+    let tl::enums::Poll::Poll(ref poll) = media.poll;
+    let tl::enums::PollResults::Results(ref results) = media.results;
+    let voters = |option: &[u8]| {
+        results.results.as_ref().and_then(|r| {
+            r.iter().find_map(|tl::enums::PollAnswerVoters::Voters(v)| {
+                (v.option == option).then_some(v.voters)
+            })
+        })
+    };
+    let answers: Vec<Value> = poll
+        .answers
+        .iter()
+        .map(|tl::enums::PollAnswer::Answer(answer)| {
+            json!({
+                "option": hex(&answer.option),
+                "text": text(&answer.text),
+                "voters": voters(&answer.option),
+            })
+        })
+        .collect();
+    json!({
+        "id": poll.id,
+        "question": text(&poll.question),
+        "closed": poll.closed,
+        "public_voters": poll.public_voters,
+        "multiple_choice": poll.multiple_choice,
+        "quiz": poll.quiz,
+        "close_date": poll.close_date,
+        "answers": answers,
+        "total_voters": results.total_voters,
+    })
+}
+
+fn text(text: &tl::enums::TextWithEntities) -> &str {
+    let tl::enums::TextWithEntities::Entities(text) = text;
+    &text.text
+}
+
+/// Offline snapshot of a page's Instant View as a standalone HTML document.
+/// Text and structure are kept, embedded media is only represented by its caption.
+pub fn instant_view_html(page: &tl::types::Page, title: Option<&str>) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    if let Some(title) = title {
+        html += &format!("<title>{}</title>\n", escape_html(title));
+    }
+    html += "</head>\n<body>\n<article>\n";
+    for block in &page.blocks {
+        page_block(block, &mut html);
+    }
+    html += &format!(
+        "</article>\n<p><a href=\"{0}\">{0}</a></p>\n</body>\n</html>\n",
+        escape_html(&page.url)
+    );
+    html
+}
+
+fn page_block(block: &tl::enums::PageBlock, html: &mut String) {
+    use tl::enums::PageBlock as B;
+    let tag = |html: &mut String, tag: &str, text: &tl::enums::RichText| {
+        *html += &format!("<{tag}>");
+        rich_text(text, html);
+        *html += &format!("</{tag}>\n");
+    };
+    match block {
+        B::Title(b) => tag(html, "h1", &b.text),
+        B::Subtitle(b) => tag(html, "h2", &b.text),
+        B::Kicker(b) => tag(html, "h4", &b.text),
+        B::AuthorDate(b) => tag(html, "address", &b.author),
+        B::Header(b) => tag(html, "h3", &b.text),
+        B::Subheader(b) => tag(html, "h4", &b.text),
+        B::Paragraph(b) => tag(html, "p", &b.text),
+        B::Preformatted(b) => tag(html, "pre", &b.text),
+        B::Footer(b) => tag(html, "footer", &b.text),
+        B::Divider => *html += "<hr>\n",
+        B::Anchor(b) => *html += &format!("<a name=\"{}\"></a>\n", escape_html(&b.name)),
+        B::Blockquote(b) => quote(&b.text, &b.caption, html),
+        B::Pullquote(b) => quote(&b.text, &b.caption, html),
+        B::List(b) => {
+            *html += "<ul>\n";
+            for item in &b.items {
+                *html += "<li>";
+                match item {
+                    tl::enums::PageListItem::Text(item) => rich_text(&item.text, html),
+                    tl::enums::PageListItem::Blocks(item) => {
+                        item.blocks.iter().for_each(|b| page_block(b, html))
+                    }
+                }
+                *html += "</li>\n";
+            }
+            *html += "</ul>\n";
+        }
+        B::OrderedList(b) => {
+            *html += "<ol>\n";
+            for item in &b.items {
+                match item {
+                    tl::enums::PageListOrderedItem::Text(item) => {
+                        *html += &format!("<li value=\"{}\">", escape_html(&item.num));
+                        rich_text(&item.text, html);
+                    }
+                    tl::enums::PageListOrderedItem::Blocks(item) => {
+                        *html += &format!("<li value=\"{}\">", escape_html(&item.num));
+                        item.blocks.iter().for_each(|b| page_block(b, html));
+                    }
+                }
+                *html += "</li>\n";
+            }
+            *html += "</ol>\n";
+        }
+        B::Details(b) => {
+            *html += if b.open {
+                "<details open>"
+            } else {
+                "<details>"
+            };
+            tag(html, "summary", &b.title);
+            b.blocks.iter().for_each(|b| page_block(b, html));
+            *html += "</details>\n";
+        }
+        B::Table(b) => {
+            *html += "<table>\n";
+            tag(html, "caption", &b.title);
+            for tl::enums::PageTableRow::Row(row) in &b.rows {
+                *html += "<tr>";
+                for tl::enums::PageTableCell::Cell(cell) in &row.cells {
+                    let name = if cell.header { "th" } else { "td" };
+                    *html += &format!("<{name}>");
+                    if let Some(ref text) = cell.text {
+                        rich_text(text, html);
+                    }
+                    *html += &format!("</{name}>");
+                }
+                *html += "</tr>\n";
+            }
+            *html += "</table>\n";
+        }
+        B::Cover(b) => page_block(&b.cover, html),
+        B::EmbedPost(b) => {
+            *html += &format!(
+                "<blockquote cite=\"{}\"><cite>{}</cite>\n",
+                escape_html(&b.url),
+                escape_html(&b.author)
+            );
+            b.blocks.iter().for_each(|b| page_block(b, html));
+            *html += "</blockquote>\n";
+            caption(&b.caption, html);
+        }
+        B::RelatedArticles(b) => {
+            tag(html, "h3", &b.title);
+            *html += "<ul>\n";
+            for tl::enums::PageRelatedArticle::Article(article) in &b.articles {
+                *html += &format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    escape_html(&article.url),
+                    escape_html(article.title.as_deref().unwrap_or(&article.url))
+                );
+            }
+            *html += "</ul>\n";
+        }
+        B::Photo(b) => caption(&b.caption, html),
+        B::Video(b) => caption(&b.caption, html),
+        B::Audio(b) => caption(&b.caption, html),
+        B::Embed(b) => caption(&b.caption, html),
+        B::Collage(b) => caption(&b.caption, html),
+        B::Slideshow(b) => caption(&b.caption, html),
+        B::Map(b) => caption(&b.caption, html),
+        B::Unsupported | B::Channel(_) => {}
+    }
+}
+
+fn quote(text: &tl::enums::RichText, caption: &tl::enums::RichText, html: &mut String) {
+    *html += "<blockquote>";
+    rich_text(text, html);
+    *html += "<cite>";
+    rich_text(caption, html);
+    *html += "</cite></blockquote>\n";
+}
+
+/// Caption of embedded media, which itself isn't part of the snapshot
+fn caption(caption: &tl::enums::PageCaption, html: &mut String) {
+    let tl::enums::PageCaption::Caption(caption) = caption;
+    *html += "<figure><figcaption>";
+    rich_text(&caption.text, html);
+    *html += " <small>";
+    rich_text(&caption.credit, html);
+    *html += "</small></figcaption></figure>\n";
+}
+
+fn rich_text(text: &tl::enums::RichText, html: &mut String) {
+    use tl::enums::RichText as T;
+    let wrap = |html: &mut String, tag: &str, text: &T| {
+        *html += &format!("<{tag}>");
+        rich_text(text, html);
+        *html += &format!("</{tag}>");
+    };
+    match text {
+        T::TextEmpty | T::TextImage(_) => {}
+        T::TextPlain(t) => *html += &escape_html(&t.text),
+        T::TextBold(t) => wrap(html, "b", &t.text),
+        T::TextItalic(t) => wrap(html, "i", &t.text),
+        T::TextUnderline(t) => wrap(html, "u", &t.text),
+        T::TextStrike(t) => wrap(html, "s", &t.text),
+        T::TextFixed(t) => wrap(html, "code", &t.text),
+        T::TextSubscript(t) => wrap(html, "sub", &t.text),
+        T::TextSuperscript(t) => wrap(html, "sup", &t.text),
+        T::TextMarked(t) => wrap(html, "mark", &t.text),
+        T::TextUrl(t) => {
+            *html += &format!("<a href=\"{}\">", escape_html(&t.url));
+            rich_text(&t.text, html);
+            *html += "</a>";
+        }
+        T::TextEmail(t) => {
+            *html += &format!("<a href=\"mailto:{}\">", escape_html(&t.email));
+            rich_text(&t.text, html);
+            *html += "</a>";
+        }
+        T::TextPhone(t) => {
+            *html += &format!("<a href=\"tel:{}\">", escape_html(&t.phone));
+            rich_text(&t.text, html);
+            *html += "</a>";
+        }
+        T::TextAnchor(t) => {
+            *html += &format!("<a name=\"{}\">", escape_html(&t.name));
+            rich_text(&t.text, html);
+            *html += "</a>";
+        }
+        T::TextConcat(t) => t.texts.iter().for_each(|t| rich_text(t, html)),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Decompresses animated sticker (gzipped Lottie animation) into `.json` next to it
+pub fn tgs_to_json(tgs_path: &Path) -> Result<()> {
+    let mut json = Vec::new();
+    GzDecoder::new(fs::File::open(tgs_path)?)
+        .read_to_end(&mut json)
+        .context("Invalid .tgs file")?;
+    fs::write(tgs_path.with_extension("json"), json)?;
+    Ok(())
+}
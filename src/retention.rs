@@ -0,0 +1,413 @@
+use crate::db::Database;
+use crate::utils::unix_timestamp;
+use anyhow::{Context, Result};
+use config::Config as AppConfig;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Media retention policy, configured in `[retention]` section
+#[derive(Deserialize, Clone)]
+pub struct Policy {
+    /// How often to prune while running, 0 disables periodic pruning
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Media older than this is pruned, first matching rule wins
+    #[serde(default)]
+    rules: Vec<Rule>,
+    /// Cap on total size of the media directory, including thumbnails, avatars and unfinished downloads.
+    /// Only media of messages and stories is evicted.
+    max_media_size_gb: Option<f64>,
+    /// Which files are evicted first when over the cap
+    #[serde(default)]
+    evict: Eviction,
+    /// Never prune media of deleted messages, it's exactly what the archive is for
+    #[serde(default = "default_true")]
+    keep_deleted: bool,
+}
+
+#[derive(Deserialize, Clone)]
+struct Rule {
+    max_age_days: u64,
+    /// Only applies to these chats, if specified
+    chats: Option<Vec<i64>>,
+    #[serde(default)]
+    exclude_chats: Vec<i64>,
+    #[serde(default = "default_true")]
+    keep_thumbnails: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum Eviction {
+    #[default]
+    Oldest,
+    Largest,
+}
+
+fn default_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Rule {
+    fn matches(&self, chat_id: Option<i64>) -> bool {
+        match chat_id {
+            Some(chat_id) => {
+                !self.exclude_chats.contains(&chat_id)
+                    && self.chats.as_ref().is_none_or(|c| c.contains(&chat_id))
+            }
+            None => self.chats.is_none(),
+        }
+    }
+}
+
+impl Policy {
+    /// Retention policy, if configured
+    pub fn from_config(settings: &AppConfig) -> Result<Option<Self>> {
+        match settings.get("retention") {
+            Ok(policy) => Ok(Some(policy)),
+            Err(config::ConfigError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e).context("Invalid retention config"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PrunedFile {
+    pub rel_path: String,
+    pub bytes: u64,
+    pub reason: &'static str,
+}
+
+/// Media file as referenced by its events, or story versions
+struct MediaFile {
+    chat_id: Option<i64>,
+    date: i64,
+    thumbnail_rel_path: Option<String>,
+    media_pruned: bool,
+    thumbnail_pruned: bool,
+    /// Media of deleted messages and stories (if kept) and of unfinished downloads
+    protected: bool,
+}
+
+/// Tables referencing media files, where pruned files are marked
+const MEDIA_TABLES: [&str; 2] = ["events", "stories"];
+
+/// Prunes media of messages and stories according to the policy, marking pruned files
+/// in `events` and `stories`.
+/// Files are marked before being deleted, so events never point at missing files unmarked.
+/// With `dry_run`, only reports what would be pruned.
+pub fn prune(
+    database: &mut Database,
+    media_path: &Path,
+    policy: &Policy,
+    dry_run: bool,
+) -> Result<Vec<PrunedFile>> {
+    let files = load_media_files(database, policy.keep_deleted)?;
+    let now = unix_timestamp();
+    let file_size = |rel_path: &str| {
+        std::iter::once(media_path.join(rel_path))
+            .chain(lottie_json(media_path, rel_path))
+            .filter_map(|path| path.metadata().ok())
+            .map(|m| m.len())
+            .sum::<u64>()
+    };
+
+    let mut pruned_media: Vec<PrunedFile> = Vec::new();
+    let mut pruned_thumbnails: Vec<PrunedFile> = Vec::new();
+    // Media files that are still kept, with size and date, as eviction candidates
+    let mut kept: Vec<(&str, u64, i64)> = Vec::new();
+
+    for (rel_path, file) in &files {
+        let rule = policy.rules.iter().find(|r| r.matches(file.chat_id));
+        let expired = rule.filter(|r| now - file.date > r.max_age_days as i64 * DAY_SECS);
+
+        let thumbnail = file.thumbnail_rel_path.as_ref();
+        if let Some(thumbnail) = thumbnail.filter(|_| !file.thumbnail_pruned) {
+            let bytes = file_size(thumbnail);
+            if expired.is_some_and(|r| !r.keep_thumbnails) && !file.protected {
+                pruned_thumbnails.push(PrunedFile {
+                    rel_path: thumbnail.clone(),
+                    bytes,
+                    reason: "age",
+                });
+            }
+        }
+
+        if file.media_pruned {
+            continue;
+        }
+        let bytes = file_size(rel_path);
+        if expired.is_some() && !file.protected {
+            pruned_media.push(PrunedFile {
+                rel_path: rel_path.clone(),
+                bytes,
+                reason: "age",
+            });
+            continue;
+        }
+        if !file.protected {
+            kept.push((rel_path.as_str(), bytes, file.date));
+        }
+    }
+
+    if let Some(max_media_size_gb) = policy.max_media_size_gb {
+        let max_size = (max_media_size_gb * GB) as u64;
+        let pruned_size: u64 = pruned_media
+            .iter()
+            .chain(&pruned_thumbnails)
+            .map(|f| f.bytes)
+            .sum();
+        let mut total_size = dir_size(media_path)?.saturating_sub(pruned_size);
+        match policy.evict {
+            Eviction::Oldest => kept.sort_by_key(|&(_, _, date)| date),
+            Eviction::Largest => kept.sort_by_key(|&(_, bytes, _)| std::cmp::Reverse(bytes)),
+        }
+        for (rel_path, bytes, _) in kept {
+            if total_size <= max_size {
+                break;
+            }
+            total_size = total_size.saturating_sub(bytes);
+            pruned_media.push(PrunedFile {
+                rel_path: rel_path.to_owned(),
+                bytes,
+                reason: "size cap",
+            });
+        }
+    }
+
+    if !dry_run {
+        let tx = database.conn_mut().transaction()?;
+        for table in MEDIA_TABLES {
+            for file in &pruned_media {
+                tx.execute(
+                    &format!(
+                        "UPDATE {table} SET media_pruned_at = ?1 \
+                         WHERE media_rel_path = ?2 AND media_pruned_at IS NULL"
+                    ),
+                    params![now, file.rel_path],
+                )?;
+            }
+            for file in &pruned_thumbnails {
+                tx.execute(
+                    &format!(
+                        "UPDATE {table} SET thumbnail_pruned_at = ?1 \
+                         WHERE thumbnail_rel_path = ?2 AND thumbnail_pruned_at IS NULL"
+                    ),
+                    params![now, file.rel_path],
+                )?;
+            }
+        }
+        tx.commit().context("Failed to mark pruned media")?;
+
+        for file in pruned_media.iter().chain(&pruned_thumbnails) {
+            let paths = [
+                Some(media_path.join(&file.rel_path)),
+                lottie_json(media_path, &file.rel_path),
+            ];
+            for path in paths.into_iter().flatten() {
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => log::warn!("Failed to remove pruned file {}: {e}", path.display()),
+                }
+            }
+        }
+    }
+
+    pruned_media.extend(pruned_thumbnails);
+    Ok(pruned_media)
+}
+
+/// Lottie JSON decompressed from an animated sticker, which goes along with the `.tgs`
+fn lottie_json(media_path: &Path, rel_path: &str) -> Option<PathBuf> {
+    rel_path
+        .ends_with(".tgs")
+        .then(|| media_path.join(rel_path).with_extension("json"))
+}
+
+/// Total size of files under `dir`, including ones not referenced by events or stories,
+/// e.g. avatars and unfinished downloads
+fn dir_size(dir: &Path) -> Result<u64> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(0);
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Media files of messages and stories, keyed by relative path, so that they're pruned in a stable order.
+/// A file can be referenced by several events, e.g. when a message is edited.
+/// Files in the shared sticker store are referenced by every message sending the sticker.
+fn load_media_files(
+    database: &Database,
+    keep_deleted: bool,
+) -> Result<BTreeMap<String, MediaFile>> {
+    let conn = database.conn();
+    // Chat ID is only known for deletions in channels, others are matched by message ID only,
+    // which only works for chats sharing message IDs
+    let mut deleted_in_chat: HashSet<(i64, i32)> = HashSet::new();
+    let mut deleted_ids: HashSet<i32> = HashSet::new();
+    let mut deleted_stories: HashSet<(i64, i32)> = HashSet::new();
+    if keep_deleted {
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT chat_id, message_id FROM events WHERE type = 'message_deleted'",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            match row.get::<_, Option<i64>>(0)? {
+                Some(chat_id) => deleted_in_chat.insert((chat_id, row.get(1)?)),
+                None => deleted_ids.insert(row.get(1)?),
+            };
+        }
+        deleted_stories = conn
+            .prepare("SELECT DISTINCT peer_id, story_id FROM stories WHERE type = 'story_deleted'")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    let pending: HashSet<(i64, i32)> = conn
+        .prepare("SELECT chat_id, message_id FROM pending_downloads")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let pending_stories: HashSet<(i64, i32)> = conn
+        .prepare("SELECT peer_id, story_id FROM pending_story_downloads")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut files = BTreeMap::new();
+    add_media_files(
+        database,
+        "SELECT media_rel_path, thumbnail_rel_path, chat_id, message_id,
+                COALESCE(date, recorded_at, 0),
+                media_pruned_at IS NOT NULL,
+                thumbnail_rel_path IS NULL OR thumbnail_pruned_at IS NOT NULL
+         FROM events WHERE media_rel_path IS NOT NULL ORDER BY id",
+        |chat_id, message_id| match chat_id {
+            Some(chat_id) => {
+                deleted_in_chat.contains(&(chat_id, message_id))
+                    || (!database.is_channel(chat_id) && deleted_ids.contains(&message_id))
+                    || pending.contains(&(chat_id, message_id))
+            }
+            None => deleted_ids.contains(&message_id),
+        },
+        &mut files,
+    )?;
+    add_media_files(
+        database,
+        "SELECT media_rel_path, thumbnail_rel_path, peer_id, story_id,
+                COALESCE(date, recorded_at),
+                media_pruned_at IS NOT NULL,
+                thumbnail_rel_path IS NULL OR thumbnail_pruned_at IS NOT NULL
+         FROM stories WHERE media_rel_path IS NOT NULL ORDER BY id",
+        |peer_id, story_id| {
+            peer_id.is_some_and(|peer_id| {
+                deleted_stories.contains(&(peer_id, story_id))
+                    || pending_stories.contains(&(peer_id, story_id))
+            })
+        },
+        &mut files,
+    )?;
+    Ok(files)
+}
+
+/// Adds media files referenced by rows of the query, protecting ones `is_protected` by chat and message (or story) ID
+fn add_media_files(
+    database: &Database,
+    sql: &str,
+    is_protected: impl Fn(Option<i64>, i32) -> bool,
+    files: &mut BTreeMap<String, MediaFile>,
+) -> Result<()> {
+    let mut stmt = database.conn().prepare(sql)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let rel_path: String = row.get(0)?;
+        let thumbnail_rel_path: Option<String> = row.get(1)?;
+        let chat_id: Option<i64> = row.get(2)?;
+        let message_id: i32 = row.get(3)?;
+        let date: i64 = row.get(4)?;
+        // Shared stickers are as old as their latest event, so stickers still in use aren't pruned
+        // only to be downloaded again. Other files are as old as their first event.
+        let shared = rel_path.starts_with(&format!("{}/", crate::STICKERS_SUBDIR));
+        let file = files.entry(rel_path).or_insert(MediaFile {
+            chat_id,
+            date,
+            thumbnail_rel_path: None,
+            media_pruned: true,
+            thumbnail_pruned: true,
+            protected: false,
+        });
+        if shared {
+            file.date = file.date.max(date);
+        }
+        file.thumbnail_rel_path = thumbnail_rel_path.or(file.thumbnail_rel_path.take());
+        file.media_pruned &= row.get::<_, bool>(5)?;
+        file.thumbnail_pruned &= row.get::<_, bool>(6)?;
+        file.protected |= is_protected(chat_id, message_id);
+    }
+    Ok(())
+}
+
+/// Prints files that were (or would be) pruned, and the total
+pub fn print_report(pruned: &[PrunedFile], dry_run: bool) {
+    for file in pruned {
+        println!("{:>12}  {:<8}  {}", file.bytes, file.reason, file.rel_path);
+    }
+    let bytes: u64 = pruned.iter().map(|f| f.bytes).sum();
+    println!(
+        "{} {} files, {bytes} bytes",
+        if dry_run { "Would prune" } else { "Pruned" },
+        pruned.len()
+    );
+}
+
+/// Prunes media periodically while the keeper is running, on its own connection
+pub async fn run(
+    db_file: PathBuf,
+    db_key: Option<String>,
+    media_path: PathBuf,
+    policy: Policy,
+) -> Result<()> {
+    let interval = Duration::from_secs(policy.interval_secs);
+    loop {
+        tokio::time::sleep(interval).await;
+        let (db_file, db_key, media_path, policy) = (
+            db_file.clone(),
+            db_key.clone(),
+            media_path.clone(),
+            policy.clone(),
+        );
+        let result = tokio::task::spawn_blocking(move || {
+            let mut database = Database::new(&db_file, db_key.as_deref())?;
+            prune(&mut database, &media_path, &policy, false)
+        })
+        .await
+        .context("Pruning panicked")?;
+        match result {
+            Ok(pruned) if !pruned.is_empty() => {
+                let bytes: u64 = pruned.iter().map(|f| f.bytes).sum();
+                log::info!("Pruned {} media files, {bytes} bytes", pruned.len());
+            }
+            Ok(_) => log::debug!("Nothing to prune"),
+            Err(e) => log::error!("Failed to prune media: {e:#}"),
+        }
+    }
+}
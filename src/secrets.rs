@@ -0,0 +1,159 @@
+use crate::utils::prompt_password;
+use anyhow::{Context, Result, bail};
+use config::{Config as AppConfig, ConfigError};
+use std::fs;
+use std::path::Path;
+
+/// Resolves a secret config value, trying the following sources in order:
+///
+/// 1. Environment variable named after the key in upper case, e.g. `TG_API_HASH`
+/// 2. File referenced by environment variable with `_FILE` suffix, e.g. `TG_API_HASH_FILE`
+/// 3. File referenced by config key with `_file` suffix, e.g. `tg_api_hash_file`
+/// 4. Plaintext config value, e.g. `tg_api_hash`
+///
+/// Returns [None] if none of these is set.
+/// Secret values themselves are never logged, only their source.
+pub fn resolve_secret_opt(settings: &AppConfig, key: &str) -> Result<Option<String>> {
+    let env_key = key.to_uppercase();
+
+    if let Some(value) = env_var(&env_key)? {
+        log::debug!("Using {key} from environment variable {env_key}");
+        return Ok(Some(value));
+    }
+
+    let env_file_key = format!("{env_key}_FILE");
+    if let Some(path) = env_var(&env_file_key)? {
+        log::debug!("Using {key} from file referenced by environment variable {env_file_key}");
+        return read_secret_file(Path::new(&path), key).map(Some);
+    }
+
+    let file_key = format!("{key}_file");
+    if let Some(path) = config_string(settings, &file_key)? {
+        log::debug!("Using {key} from file referenced by config key {file_key}");
+        return read_secret_file(Path::new(&path), key).map(Some);
+    }
+
+    if let Some(value) = config_string(settings, key)? {
+        log::debug!("Using {key} from config");
+        return Ok(Some(value));
+    }
+
+    Ok(None)
+}
+
+/// Same as [resolve_secret_opt], but falls back to prompting the user interactively.
+pub fn resolve_secret(settings: &AppConfig, key: &str, prompt: &str) -> Result<String> {
+    if let Some(value) = resolve_secret_opt(settings, key)? {
+        return Ok(value);
+    }
+    log::debug!("{key} is not configured, prompting");
+    let value = prompt_password(prompt).with_context(|| format!("Failed to prompt for {key}"))?;
+    if value.is_empty() {
+        bail!("{key} must not be empty");
+    }
+    Ok(value)
+}
+
+fn env_var(name: &str) -> Result<Option<String>> {
+    match std::env::var(name) {
+        Ok(v) if v.is_empty() => Ok(None),
+        Ok(v) => Ok(Some(v)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Invalid environment variable {name}")),
+    }
+}
+
+fn config_string(settings: &AppConfig, key: &str) -> Result<Option<String>> {
+    match settings.get_string(key) {
+        Ok(v) if v.is_empty() => Ok(None),
+        Ok(v) => Ok(Some(v)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Invalid config value {key}")),
+    }
+}
+
+fn read_secret_file(path: &Path, key: &str) -> Result<String> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {key} from {}", path.display()))?;
+    // Secret files usually end with a newline that is not a part of the secret
+    let value = content.trim_end_matches(['\r', '\n']);
+    if value.is_empty() {
+        bail!("File {} for {key} is empty", path.display());
+    }
+    Ok(value.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(values: &[(&str, &str)]) -> AppConfig {
+        values
+            .iter()
+            .fold(AppConfig::builder(), |builder, &(key, value)| {
+                builder.set_override(key, value).unwrap()
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn secret_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "tg-keeper-secret-test-{}-{name}",
+            std::process::id()
+        ));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn config_sources() {
+        let key = "tg_keeper_test_config_secret";
+        assert_eq!(resolve_secret_opt(&settings(&[]), key).unwrap(), None);
+        assert_eq!(
+            resolve_secret_opt(&settings(&[(key, "plain")]), key).unwrap(),
+            Some("plain".to_owned())
+        );
+
+        // File takes precedence over plaintext value, and its trailing newline isn't a part of the secret
+        let path = secret_file("config", "from file\n");
+        let file_key = format!("{key}_file");
+        let both = settings(&[(key, "plain"), (file_key.as_str(), path.to_str().unwrap())]);
+        let value = resolve_secret_opt(&both, key);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(value.unwrap(), Some("from file".to_owned()));
+    }
+
+    #[test]
+    fn empty_or_missing_file() {
+        let key = "tg_keeper_test_file_secret";
+        let file_key = format!("{key}_file");
+        let path = secret_file("empty", "\n");
+        let empty = settings(&[(file_key.as_str(), path.to_str().unwrap())]);
+        let result = resolve_secret_opt(&empty, key);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+
+        let missing = settings(&[(file_key.as_str(), path.to_str().unwrap())]);
+        assert!(resolve_secret_opt(&missing, key).is_err());
+    }
+
+    #[test]
+    fn env_sources() {
+        // Names are unique to this test, as environment is shared by tests running in parallel
+        let key = "tg_keeper_test_env_secret";
+        let file_key = "tg_keeper_test_env_file_secret";
+        let path = secret_file("env", "from env file\r\n");
+        // SAFETY: No other test reads or writes these variables
+        unsafe {
+            std::env::set_var("TG_KEEPER_TEST_ENV_SECRET", "from env");
+            std::env::set_var("TG_KEEPER_TEST_ENV_FILE_SECRET_FILE", &path);
+        }
+        let config = settings(&[(key, "plain"), (file_key, "plain")]);
+        let from_env = resolve_secret_opt(&config, key);
+        let from_env_file = resolve_secret_opt(&config, file_key);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(from_env.unwrap(), Some("from env".to_owned()));
+        assert_eq!(from_env_file.unwrap(), Some("from env file".to_owned()));
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use grammers_session::Session;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Header identifying an encrypted session file, followed by format version
+const MAGIC: &[u8] = b"TGKSESS\x01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Session file wrapper which transparently encrypts session at rest
+/// if a passphrase is provided.
+///
+/// Encrypted file layout: `MAGIC | salt | nonce | ciphertext`,
+/// key is derived from passphrase and salt using Argon2id, cipher is XChaCha20-Poly1305.
+pub struct SessionFile {
+    path: PathBuf,
+    cipher: Option<SessionCipher>,
+}
+
+struct SessionCipher {
+    salt: [u8; SALT_LEN],
+    cipher: XChaCha20Poly1305,
+}
+
+impl SessionFile {
+    /// Checks if the existing file at given path is an encrypted session.
+    pub fn is_encrypted(path: &Path) -> Result<bool> {
+        if !path.exists() {
+            return Ok(false);
+        }
+        let content = fs::read(path).context("Failed to read session file")?;
+        Ok(content.starts_with(MAGIC))
+    }
+
+    /// Opens (or creates) a session file.
+    /// Without passphrase, session is stored in plaintext, as `grammers` does it.
+    /// With passphrase, session is encrypted, and existing plaintext session is migrated in place.
+    pub fn open(path: &Path, passphrase: Option<&str>) -> Result<(Self, Session)> {
+        let content = if path.exists() {
+            Some(fs::read(path).context("Failed to read session file")?)
+        } else {
+            None
+        };
+
+        let Some(passphrase) = passphrase else {
+            ensure!(
+                !content.as_ref().is_some_and(|c| c.starts_with(MAGIC)),
+                "Session file is encrypted, but no passphrase was provided"
+            );
+            let session_file = SessionFile {
+                path: path.to_owned(),
+                cipher: None,
+            };
+            let session = Session::load_file_or_create(path)?;
+            return Ok((session_file, session));
+        };
+
+        match content {
+            Some(content) if content.starts_with(MAGIC) => {
+                let content = &content[MAGIC.len()..];
+                ensure!(
+                    content.len() > SALT_LEN + NONCE_LEN,
+                    "Encrypted session file is truncated"
+                );
+                let (salt, content) = content.split_at(SALT_LEN);
+                let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+                let cipher = SessionCipher::new(passphrase, salt.try_into().unwrap())?;
+                let plaintext = cipher
+                    .cipher
+                    .decrypt(XNonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| anyhow!("Failed to decrypt session file, wrong passphrase?"))?;
+                let session = Session::load(&plaintext).context("Failed to load session")?;
+                let session_file = SessionFile {
+                    path: path.to_owned(),
+                    cipher: Some(cipher),
+                };
+                Ok((session_file, session))
+            }
+            content => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                let session_file = SessionFile {
+                    path: path.to_owned(),
+                    cipher: Some(SessionCipher::new(passphrase, salt)?),
+                };
+                let session = match content {
+                    Some(content) => {
+                        log::info!("Encrypting existing plaintext session file");
+                        let session = Session::load(&content).context("Failed to load session")?;
+                        session_file.save(&session)?;
+                        session
+                    }
+                    None => Session::new(),
+                };
+                Ok((session_file, session))
+            }
+        }
+    }
+
+    pub fn save(&self, session: &Session) -> Result<()> {
+        let plaintext = session.save();
+        let content = match self.cipher {
+            Some(ref cipher) => {
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = cipher
+                    .cipher
+                    .encrypt(&nonce, plaintext.as_slice())
+                    .map_err(|_| anyhow!("Failed to encrypt session"))?;
+                let mut content =
+                    Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+                content.extend_from_slice(MAGIC);
+                content.extend_from_slice(&cipher.salt);
+                content.extend_from_slice(&nonce);
+                content.extend_from_slice(&ciphertext);
+                content
+            }
+            None => plaintext,
+        };
+
+        // Write to a temporary file first so that the session is never left half-written
+        let tmp_path = self.path.with_extension("session.tmp");
+        fs::write(&tmp_path, content).context("Failed to write session file")?;
+        fs::rename(&tmp_path, &self.path).context("Failed to replace session file")?;
+        Ok(())
+    }
+}
+
+impl SessionCipher {
+    fn new(passphrase: &str, salt: [u8; SALT_LEN]) -> Result<Self> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| anyhow!("Failed to derive session key: {e}"))?;
+        Ok(SessionCipher {
+            salt,
+            cipher: XChaCha20Poly1305::new(&key),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tg-keeper-session-test-{}-{name}.session",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn encryption_round_trip() {
+        let path = session_path("round-trip");
+        let (session_file, session) = SessionFile::open(&path, Some("passphrase")).unwrap();
+        session_file.save(&session).unwrap();
+        assert!(SessionFile::is_encrypted(&path).unwrap());
+
+        let (_, loaded) = SessionFile::open(&path, Some("passphrase")).unwrap();
+        let wrong_passphrase = SessionFile::open(&path, Some("wrong")).map(|_| ());
+        let no_passphrase = SessionFile::open(&path, None).map(|_| ());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.save(), session.save());
+        assert!(wrong_passphrase.is_err());
+        assert!(no_passphrase.is_err());
+    }
+
+    #[test]
+    fn plaintext_migration() {
+        let path = session_path("migration");
+        let (session_file, session) = SessionFile::open(&path, None).unwrap();
+        session_file.save(&session).unwrap();
+        assert!(!SessionFile::is_encrypted(&path).unwrap());
+
+        // Existing plaintext session is encrypted in place once a passphrase is provided
+        SessionFile::open(&path, Some("passphrase")).unwrap();
+        let encrypted = SessionFile::is_encrypted(&path).unwrap();
+        let (_, loaded) = SessionFile::open(&path, Some("passphrase")).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(encrypted);
+        assert_eq!(loaded.save(), session.save());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::actions;
+use crate::attribution::Attribution;
+use crate::utils::*;
+use anyhow::{Context, Result};
+use config::Config as AppConfig;
+use grammers_client::{grammers_tl_types as tl, types};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::{Arc, Mutex, mpsc};
+
+/// Normalized archive event, passed to sinks after it has been committed to the database
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    MessageNew(MessageEvent),
+    MessageEdited(MessageEvent),
+    MessageDeleted {
+        /// Unknown outside channels, see `Database::save_messages_deleted`
+        chat_id: Option<i64>,
+        message_ids: Vec<i32>,
+    },
+    ChatUpdated {
+        chat_id: i64,
+        chat_type: &'static str,
+        name: Option<String>,
+    },
+    AvatarChanged {
+        chat_id: i64,
+        /// [None] if the photo was removed
+        photo_id: Option<i64>,
+        rel_path: Option<String>,
+    },
+    StoryNew(StoryEvent),
+    StoryEdited(StoryEvent),
+    StoryDeleted {
+        peer_id: i64,
+        story_id: i32,
+    },
+    /// Channel messages up to `available_min_id` were hidden or cleared on Telegram
+    HistoryCleared {
+        chat_id: i64,
+        available_min_id: i32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageEvent {
+    pub chat_id: Option<i64>,
+    pub message_id: i32,
+    pub date: Option<i32>,
+    pub sender_id: Option<i64>,
+    pub text: Option<String>,
+    pub media_rel_path: Option<String>,
+    pub thumbnail_rel_path: Option<String>,
+    /// Decoded action of service messages
+    pub action: Option<serde_json::Value>,
+    /// Set for self-destructing media
+    pub ttl_seconds: Option<i32>,
+    /// Shared by messages of the same album
+    pub grouped_id: Option<i64>,
+    /// Resolved sender, forward origin, inline bot and post author
+    pub attribution: Option<Attribution>,
+}
+
+impl MessageEvent {
+    pub fn new(
+        raw_message: &tl::enums::Message,
+        media: Option<&DownloadedMedia>,
+        attribution: Option<Attribution>,
+    ) -> Self {
+        MessageEvent {
+            chat_id: raw_message.chat_id(),
+            message_id: raw_message.id(),
+            date: raw_message.date(),
+            sender_id: raw_message.sender_id(),
+            text: raw_message.text().map(str::to_owned),
+            media_rel_path: media.map(|m| m.media_rel_path.clone()),
+            thumbnail_rel_path: media.and_then(|m| m.thumbnail_rel_path.clone()),
+            action: match raw_message {
+                tl::enums::Message::Service(service) => Some(actions::decode(service)),
+                _ => None,
+            },
+            ttl_seconds: media_ttl(raw_message),
+            grouped_id: match raw_message {
+                tl::enums::Message::Message(message) => message.grouped_id,
+                _ => None,
+            },
+            attribution,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoryEvent {
+    pub peer_id: i64,
+    pub story_id: i32,
+    pub date: i32,
+    pub expire_date: i32,
+    pub caption: Option<String>,
+    pub media_rel_path: Option<String>,
+    pub thumbnail_rel_path: Option<String>,
+}
+
+impl StoryEvent {
+    pub fn new(
+        peer_id: i64,
+        story: &tl::types::StoryItem,
+        media: Option<&DownloadedMedia>,
+    ) -> Self {
+        StoryEvent {
+            peer_id,
+            story_id: story.id,
+            date: story.date,
+            expire_date: story.expire_date,
+            caption: story.caption.clone(),
+            media_rel_path: media.map(|m| m.media_rel_path.clone()),
+            thumbnail_rel_path: media.and_then(|m| m.thumbnail_rel_path.clone()),
+        }
+    }
+}
+
+impl Event {
+    pub fn chat_updated(chat: &types::Chat) -> Self {
+        Event::ChatUpdated {
+            chat_id: chat.id(),
+            chat_type: match chat {
+                types::Chat::User(_) => "user",
+                types::Chat::Group(_) => "group",
+                types::Chat::Channel(_) => "channel",
+            },
+            name: chat.name().map(str::to_owned),
+        }
+    }
+}
+
+/// Receiver of archived events, e.g. for streaming them to other tools.
+/// Sink errors are logged, and never interrupt archiving.
+pub trait EventSink: Send {
+    fn send(&mut self, event: &Event) -> Result<()>;
+}
+
+/// Writes events as JSON Lines into a file, or stdout
+pub struct JsonLinesSink {
+    out: Box<dyn Write + Send>,
+}
+
+impl JsonLinesSink {
+    pub fn stdout() -> Self {
+        JsonLinesSink {
+            out: Box::new(std::io::stdout()),
+        }
+    }
+
+    pub fn file(path: &std::path::Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(JsonLinesSink {
+            out: Box::new(file),
+        })
+    }
+}
+
+impl EventSink for JsonLinesSink {
+    fn send(&mut self, event: &Event) -> Result<()> {
+        serde_json::to_writer(&mut self.out, event)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Events queued for a client of [UnixSocketSink], before it's considered to have fallen behind
+#[cfg(unix)]
+const CLIENT_QUEUE_LEN: usize = 1024;
+/// Write timeout for clients of [UnixSocketSink], so that writers of clients that stopped reading end
+#[cfg(unix)]
+const CLIENT_WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Queue of JSON lines to write to a client of [UnixSocketSink]
+#[cfg(unix)]
+type ClientQueue = mpsc::SyncSender<Arc<[u8]>>;
+
+/// Streams events as JSON Lines to every client connected to a Unix domain socket.
+/// Clients only receive events that happened after they connected.
+/// Each client is written to by a thread of its own from a bounded queue, so slow clients never stall archiving,
+/// and clients falling behind by more than [CLIENT_QUEUE_LEN] events are disconnected.
+#[cfg(unix)]
+pub struct UnixSocketSink {
+    path: PathBuf,
+    clients: Arc<Mutex<Vec<ClientQueue>>>,
+}
+
+#[cfg(unix)]
+impl UnixSocketSink {
+    pub fn bind(path: PathBuf) -> Result<Self> {
+        // Socket file is left behind if the keeper wasn't stopped properly
+        if path.exists() {
+            fs::remove_file(&path).context("Failed to remove stale socket")?;
+        }
+        let listener = std::os::unix::net::UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind socket {}", path.display()))?;
+        let clients: Arc<Mutex<Vec<ClientQueue>>> = Arc::default();
+        let accepted = Arc::clone(&clients);
+        let socket_path = path.clone();
+        std::thread::Builder::new()
+            .name("event-socket".to_owned())
+            .spawn(move || accept_clients(listener, &socket_path, &accepted))
+            .context("Failed to start event socket thread")?;
+        Ok(UnixSocketSink { path, clients })
+    }
+}
+
+/// Accepts clients for as long as the process runs, starting a writer thread for each of them
+#[cfg(unix)]
+fn accept_clients(
+    listener: std::os::unix::net::UnixListener,
+    path: &std::path::Path,
+    clients: &Mutex<Vec<ClientQueue>>,
+) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept event stream client: {e}");
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)) {
+            log::warn!("Failed to set up event stream client: {e}");
+            continue;
+        }
+        let (sender, receiver) = mpsc::sync_channel::<Arc<[u8]>>(CLIENT_QUEUE_LEN);
+        let writer = std::thread::Builder::new()
+            .name("event-socket-client".to_owned())
+            .spawn(move || {
+                // Ends once the client is dropped by the sink, or disconnects
+                for line in receiver {
+                    if let Err(e) = stream.write_all(&line) {
+                        log::debug!("Event stream client disconnected: {e}");
+                        return;
+                    }
+                }
+            });
+        match writer {
+            Ok(_) => {
+                log::debug!("Event stream client connected to {}", path.display());
+                clients.lock().unwrap().push(sender);
+            }
+            Err(e) => log::warn!("Failed to start event stream client thread: {e}"),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl EventSink for UnixSocketSink {
+    fn send(&mut self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let line: Arc<[u8]> = line.into();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.try_send(Arc::clone(&line)) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    log::warn!("Event stream client fell behind, disconnecting it");
+                    false
+                }
+                // Writer ended, the client has disconnected
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            });
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketSink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+//
+// Configuration
+//
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum SinkConfig {
+    /// `-` stands for stdout
+    Jsonl { path: PathBuf },
+    #[cfg(unix)]
+    UnixSocket { path: PathBuf },
+}
+
+pub fn sinks_from_config(settings: &AppConfig) -> Result<Vec<Box<dyn EventSink>>> {
+    let configs: Vec<SinkConfig> = match settings.get("sinks") {
+        Ok(configs) => configs,
+        Err(config::ConfigError::NotFound(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Invalid sinks config"),
+    };
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::with_capacity(configs.len());
+    for config in configs {
+        match config {
+            SinkConfig::Jsonl { path } if path.as_os_str() == "-" => {
+                sinks.push(Box::new(JsonLinesSink::stdout()))
+            }
+            SinkConfig::Jsonl { path } => sinks.push(Box::new(JsonLinesSink::file(&path)?)),
+            #[cfg(unix)]
+            SinkConfig::UnixSocket { path } => sinks.push(Box::new(UnixSocketSink::bind(path)?)),
+        }
+    }
+    if !sinks.is_empty() {
+        log::info!("Configured {} event sink(s)", sinks.len());
+    }
+    Ok(sinks)
+}
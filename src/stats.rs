@@ -0,0 +1,329 @@
+use crate::db::Database;
+use crate::utils::*;
+use anyhow::{Context, Result};
+use grammers_client::grammers_tl_types::{self as tl, Deserializable};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub chats: Vec<ChatStats>,
+    pub months: Vec<PeriodStats>,
+    pub top_senders: Vec<SenderStats>,
+    pub media_types: Vec<MediaTypeStats>,
+    /// Number of new messages by hour of day, in local time
+    pub hours: [u64; 24],
+}
+
+#[derive(Serialize, Default)]
+pub struct ChatStats {
+    pub chat_id: i64,
+    pub name: Option<String>,
+    pub messages: u64,
+    pub edits: u64,
+    pub deletions: u64,
+    pub media_files: u64,
+    pub media_bytes: u64,
+    /// Size of the chat media directory, including thumbnails
+    pub disk_usage_bytes: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct PeriodStats {
+    pub period: String,
+    pub messages: u64,
+    pub edits: u64,
+    pub deletions: u64,
+}
+
+#[derive(Serialize)]
+pub struct SenderStats {
+    pub sender_id: i64,
+    pub name: Option<String>,
+    pub messages: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct MediaTypeStats {
+    pub media_type: &'static str,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Gathers statistics over the whole archive.
+/// Deletions outside channels come without chat, and are attributed to the chat of their archived version, if any.
+/// History of groups migrated to supergroups is attributed to the supergroup.
+pub fn gather(database: &Database, media_path: &Path, top_senders: usize) -> Result<Stats> {
+    let mut chats: HashMap<i64, ChatStats> = HashMap::new();
+    let mut months: BTreeMap<String, PeriodStats> = BTreeMap::new();
+    let mut senders: HashMap<i64, u64> = HashMap::new();
+    let mut media_types: HashMap<&'static str, MediaTypeStats> = HashMap::new();
+    let mut hours = [0u64; 24];
+    // Chat ID is only known for deletions in channels, others are resolved from other events of the message.
+    // Only chats sharing message IDs are indexed, as channels number their messages on their own.
+    let mut message_chats: HashMap<i32, i64> = HashMap::new();
+    let mut deletions: Vec<(i32, Option<String>)> = Vec::new();
+
+    let mut stmt = database.conn().prepare(
+        "SELECT merged_chat_id, message_id, type, serialized, media_rel_path,
+                strftime('%Y-%m', COALESCE(date, recorded_at), 'unixepoch', 'localtime'),
+                CAST(strftime('%H', date, 'unixepoch', 'localtime') AS INTEGER),
+                sender_id, chat_id
+         FROM events_merged ORDER BY id",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let chat_id: Option<i64> = row.get(0)?;
+        let message_id: i32 = row.get(1)?;
+        let event_type: String = row.get(2)?;
+        let serialized: Option<Vec<u8>> = row.get(3)?;
+        let media_rel_path: Option<String> = row.get(4)?;
+        let month: Option<String> = row.get(5)?;
+        let hour: Option<usize> = row.get(6)?;
+        let sender_id: Option<i64> = row.get(7)?;
+        // Before migration to supergroup, if any
+        let original_chat_id: Option<i64> = row.get(8)?;
+
+        let Some(chat_id) = chat_id else {
+            deletions.push((message_id, month));
+            continue;
+        };
+        if original_chat_id.is_some_and(|id| !database.is_channel(id)) {
+            message_chats.insert(message_id, chat_id);
+        }
+
+        let chat = chats.entry(chat_id).or_insert_with(|| ChatStats {
+            chat_id,
+            ..Default::default()
+        });
+        let period = month.map(|month| {
+            months.entry(month.clone()).or_insert_with(|| PeriodStats {
+                period: month,
+                ..Default::default()
+            })
+        });
+
+        let is_new = match event_type.as_str() {
+            "message_edited" => {
+                chat.edits += 1;
+                if let Some(period) = period {
+                    period.edits += 1;
+                }
+                false
+            }
+            "message_deleted" => {
+                chat.deletions += 1;
+                if let Some(period) = period {
+                    period.deletions += 1;
+                }
+                false
+            }
+            _ => {
+                chat.messages += 1;
+                if let Some(period) = period {
+                    period.messages += 1;
+                }
+                if let Some(hour) = hour {
+                    hours[hour % 24] += 1;
+                }
+                true
+            }
+        };
+
+        let message = serialized
+            .map(|s| tl::enums::Message::from_bytes(&s))
+            .transpose()
+            .context("Failed to deserialize archived message")?;
+
+        // Older events have no sender column, imported ones have no serialized message
+        let sender_id = sender_id.or_else(|| message.as_ref().and_then(|m| m.sender_id()));
+        if let Some(sender_id) = sender_id.filter(|_| is_new) {
+            *senders.entry(sender_id).or_default() += 1;
+        }
+
+        // Edits re-download media, only count it once
+        if let Some(media_rel_path) = media_rel_path.filter(|_| is_new) {
+            let bytes = media_path
+                .join(&media_rel_path)
+                .metadata()
+                .map(|m| m.len())
+                .unwrap_or(0);
+            chat.media_files += 1;
+            chat.media_bytes += bytes;
+            let media_type = match message {
+                Some(tl::enums::Message::Message(tl::types::Message {
+                    media: Some(ref media),
+                    ..
+                })) => describe_media(media),
+                _ => "unknown",
+            };
+            let media_type_stats = media_types.entry(media_type).or_insert_with(|| {
+                MediaTypeStats {
+                    media_type,
+                    ..Default::default()
+                }
+            });
+            media_type_stats.files += 1;
+            media_type_stats.bytes += bytes;
+        }
+    }
+
+    for (message_id, month) in deletions {
+        if let Some(chat_id) = message_chats.get(&message_id) {
+            chats.get_mut(chat_id).unwrap().deletions += 1;
+        }
+        if let Some(month) = month {
+            months
+                .entry(month.clone())
+                .or_insert_with(|| PeriodStats {
+                    period: month,
+                    ..Default::default()
+                })
+                .deletions += 1;
+        }
+    }
+
+    for chat in chats.values_mut() {
+        chat.name = database
+            .chat(chat.chat_id)
+            .and_then(|c| c.name())
+            .map(str::to_owned);
+        chat.disk_usage_bytes = dir_size(&media_path.join(format!("chat_{}", chat.chat_id)));
+    }
+
+    let mut chats: Vec<ChatStats> = chats.into_values().collect();
+    chats.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.chat_id.cmp(&b.chat_id)));
+
+    let mut senders: Vec<SenderStats> = senders
+        .into_iter()
+        .map(|(sender_id, messages)| SenderStats {
+            sender_id,
+            name: database
+                .chat(sender_id)
+                .and_then(|c| c.name())
+                .map(str::to_owned),
+            messages,
+        })
+        .collect();
+    senders.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.sender_id.cmp(&b.sender_id)));
+    senders.truncate(top_senders);
+
+    let mut media_types: Vec<MediaTypeStats> = media_types.into_values().collect();
+    media_types.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+    Ok(Stats {
+        chats,
+        months: months.into_values().collect(),
+        top_senders: senders,
+        media_types,
+        hours,
+    })
+}
+
+/// Prints statistics as human-readable tables
+pub fn print_table(stats: &Stats) {
+    println!("Chats:");
+    println!(
+        "{:>16}  {:<32}  {:>8}  {:>6}  {:>9}  {:>6}  {:>10}  {:>10}",
+        "ID", "Name", "Messages", "Edits", "Deletions", "Media", "Media size", "Disk usage"
+    );
+    for chat in &stats.chats {
+        println!(
+            "{:>16}  {:<32}  {:>8}  {:>6}  {:>9}  {:>6}  {:>10}  {:>10}",
+            chat.chat_id,
+            truncate(chat.name.as_deref().unwrap_or("<unknown>"), 32),
+            chat.messages,
+            chat.edits,
+            chat.deletions,
+            chat.media_files,
+            format_bytes(chat.media_bytes),
+            format_bytes(chat.disk_usage_bytes),
+        );
+    }
+
+    println!();
+    println!("By month:");
+    println!(
+        "{:<8}  {:>8}  {:>6}  {:>9}",
+        "Month", "Messages", "Edits", "Deletions"
+    );
+    for period in &stats.months {
+        println!(
+            "{:<8}  {:>8}  {:>6}  {:>9}",
+            period.period, period.messages, period.edits, period.deletions
+        );
+    }
+
+    println!();
+    println!("Top senders:");
+    println!("{:>16}  {:<32}  {:>8}", "ID", "Name", "Messages");
+    for sender in &stats.top_senders {
+        println!(
+            "{:>16}  {:<32}  {:>8}",
+            sender.sender_id,
+            truncate(sender.name.as_deref().unwrap_or("<unknown>"), 32),
+            sender.messages
+        );
+    }
+
+    println!();
+    println!("Media by type:");
+    println!("{:<16}  {:>6}  {:>10}", "Type", "Files", "Size");
+    for media_type in &stats.media_types {
+        println!(
+            "{:<16}  {:>6}  {:>10}",
+            media_type.media_type,
+            media_type.files,
+            format_bytes(media_type.bytes)
+        );
+    }
+
+    println!();
+    println!("Messages by hour:");
+    let max = stats.hours.iter().copied().max().unwrap_or(0).max(1);
+    for (hour, count) in stats.hours.iter().enumerate() {
+        let bar = "#".repeat((count * 40 / max) as usize);
+        println!("{hour:02}:00  {count:>8}  {bar}");
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.metadata() {
+            Ok(m) if m.is_dir() => dir_size(&e.path()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        s.to_owned()
+    } else {
+        let mut truncated: String = s.chars().take(max_chars - 1).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
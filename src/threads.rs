@@ -0,0 +1,253 @@
+use crate::db::Database;
+use crate::utils::*;
+use anyhow::{Context, Result};
+use grammers_client::grammers_tl_types as tl;
+use rusqlite::params;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// ID of the General topic of forums, whose messages have no topic specified
+pub const GENERAL_TOPIC_ID: i32 = 1;
+
+/// Where a message belongs in the conversation structure
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplyTo {
+    /// Replied message, [None] if the message is only posted in a topic
+    pub msg_id: Option<i32>,
+    /// Chat of the replied message, if it's in another chat
+    pub peer_id: Option<i64>,
+    /// Forum topic, which is identified by the message that created it.
+    /// [None] for the General topic and chats without topics.
+    pub topic_id: Option<i32>,
+}
+
+/// Decodes reply relation and forum topic of a message
+pub fn reply_to(raw_message: &tl::enums::Message) -> ReplyTo {
+    let header = match raw_message {
+        tl::enums::Message::Message(message) => message.reply_to.as_ref(),
+        tl::enums::Message::Service(message) => {
+            if let tl::enums::MessageAction::TopicCreate(_) = message.action {
+                return ReplyTo {
+                    topic_id: Some(message.id),
+                    ..Default::default()
+                };
+            }
+            message.reply_to.as_ref()
+        }
+        tl::enums::Message::Empty(_) => None,
+    };
+    // Replies to stories are not part of the conversation
+    let Some(tl::enums::MessageReplyHeader::Header(header)) = header else {
+        return ReplyTo::default();
+    };
+    // Messages in a topic reply to its creation message, and replies within it also have the topic as top ID
+    let topic_id = header
+        .forum_topic
+        .then_some(header.reply_to_top_id.or(header.reply_to_msg_id))
+        .flatten();
+    let msg_id = if header.forum_topic && header.reply_to_top_id.is_none() {
+        None
+    } else {
+        header.reply_to_msg_id
+    };
+    ReplyTo {
+        msg_id,
+        peer_id: header.reply_to_peer_id.as_ref().and_then(|p| p.chat_id()),
+        topic_id,
+    }
+}
+
+/// Forum topic with its archived messages, or the whole chat if it's not a forum
+#[derive(Serialize)]
+pub struct Topic {
+    pub topic_id: i32,
+    pub title: Option<String>,
+    pub closed: bool,
+    pub messages: Vec<ThreadMessage>,
+}
+
+/// Latest archived version of a message
+#[derive(Serialize)]
+pub struct ThreadMessage {
+    pub message_id: i32,
+    pub date: Option<i64>,
+    /// In local time
+    pub time: Option<String>,
+    pub sender_id: Option<i64>,
+    pub sender_name: Option<String>,
+    pub text: Option<String>,
+    pub media_rel_path: Option<String>,
+    pub action: Option<Value>,
+    pub reply_to_msg_id: Option<i32>,
+    /// Set if the replied message is in another chat
+    pub reply_to_peer_id: Option<i64>,
+    pub deleted: bool,
+    /// Hidden or cleared from the chat history on Telegram, see `available_messages` table
+    pub unavailable: bool,
+}
+
+/// Archived messages of a chat grouped by forum topic, optionally only a single topic.
+/// Chats without topics have all messages in the General topic.
+pub fn topics(database: &Database, chat_id: i64, topic_id: Option<i32>) -> Result<Vec<Topic>> {
+    let conn = database.conn();
+    let available_min_id: Option<i32> = conn
+        .query_row(
+            "SELECT MAX(available_min_id) FROM available_messages WHERE chat_id = ?1",
+            params![chat_id],
+            |row| row.get(0),
+        )
+        .context("Failed to load available messages")?;
+
+    let mut stmt = conn.prepare(
+        "SELECT topic_id, title, closed FROM forum_topics WHERE chat_id = ?1 ORDER BY topic_id",
+    )?;
+    let forum_topics = stmt
+        .query_map(params![chat_id], |row| {
+            Ok(Topic {
+                topic_id: row.get(0)?,
+                title: row.get(1)?,
+                closed: row.get::<_, Option<bool>>(2)?.unwrap_or_default(),
+                messages: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to load forum topics")?;
+    let name_of = |id: i64| database.chat(id).and_then(|c| c.name()).map(str::to_owned);
+    let mut topics = vec![Topic {
+        topic_id: GENERAL_TOPIC_ID,
+        title: if forum_topics.is_empty() {
+            name_of(chat_id)
+        } else {
+            Some("General".to_owned())
+        },
+        closed: false,
+        messages: Vec::new(),
+    }];
+    topics.extend(forum_topics);
+
+    // Chat ID is only known for deletions in channels (and so forums), others are matched
+    // by message ID only, which only works for chats sharing message IDs
+    let match_deleted = !database.is_channel(chat_id);
+    let mut stmt = conn.prepare(
+        "SELECT e.message_id, e.date, datetime(e.date, 'unixepoch', 'localtime'), e.sender_id, e.sender_name,
+                e.text, e.media_rel_path, e.action, e.reply_to_msg_id, e.reply_to_peer_id, e.topic_id,
+                EXISTS (SELECT 1 FROM events d WHERE d.type = 'message_deleted' AND d.message_id = e.message_id
+                        AND (d.chat_id = e.chat_id OR (?2 AND d.chat_id IS NULL)))
+         FROM events e
+         WHERE e.id IN (SELECT MAX(id) FROM events WHERE chat_id = ?1 GROUP BY message_id)
+         ORDER BY e.message_id",
+    )?;
+    let mut rows = stmt.query(params![chat_id, match_deleted])?;
+    while let Some(row) = rows.next()? {
+        let message_id: i32 = row.get(0)?;
+        let sender_id: Option<i64> = row.get(3)?;
+        let message = ThreadMessage {
+            message_id,
+            date: row.get(1)?,
+            time: row.get(2)?,
+            sender_id,
+            // Only known for messages archived with their attribution
+            sender_name: row
+                .get::<_, Option<String>>(4)?
+                .or(sender_id.and_then(name_of)),
+            text: row.get(5)?,
+            media_rel_path: row.get(6)?,
+            action: row.get(7)?,
+            reply_to_msg_id: row.get(8)?,
+            reply_to_peer_id: row.get(9)?,
+            deleted: row.get(11)?,
+            unavailable: available_min_id.is_some_and(|min_id| message_id <= min_id),
+        };
+        let message_topic_id = row.get::<_, Option<i32>>(10)?.unwrap_or(GENERAL_TOPIC_ID);
+        match topics.iter_mut().find(|t| t.topic_id == message_topic_id) {
+            Some(topic) => topic.messages.push(message),
+            // Topic created before it was archived
+            None => topics.push(Topic {
+                topic_id: message_topic_id,
+                title: None,
+                closed: false,
+                messages: vec![message],
+            }),
+        }
+    }
+
+    topics.retain(|t| topic_id.map_or(!t.messages.is_empty(), |id| t.topic_id == id));
+    Ok(topics)
+}
+
+pub fn print_topics(topics: &[Topic]) {
+    // Replies can quote messages from other topics
+    let by_id: HashMap<i32, &ThreadMessage> = topics
+        .iter()
+        .flat_map(|t| &t.messages)
+        .map(|m| (m.message_id, m))
+        .collect();
+    for topic in topics {
+        println!(
+            "=== {} (#{}){} ===",
+            topic.title.as_deref().unwrap_or("<unknown topic>"),
+            topic.topic_id,
+            if topic.closed { ", closed" } else { "" }
+        );
+        for message in &topic.messages {
+            if let Some(reply_to_msg_id) = message.reply_to_msg_id {
+                let quote = match (message.reply_to_peer_id, by_id.get(&reply_to_msg_id)) {
+                    (Some(peer_id), _) => format!("<message {reply_to_msg_id} in chat {peer_id}>"),
+                    (None, Some(replied)) => {
+                        format!("{}: {}", sender(replied), first_line(replied))
+                    }
+                    (None, None) => format!("<message {reply_to_msg_id} not archived>"),
+                };
+                println!("    > {quote}");
+            }
+            let mut flags = Vec::new();
+            if message.deleted {
+                flags.push("deleted");
+            }
+            if message.unavailable {
+                flags.push("unavailable");
+            }
+            println!(
+                "{} #{} {}: {}{}",
+                message.time.as_deref().unwrap_or("<no date>"),
+                message.message_id,
+                sender(message),
+                first_line(message),
+                if flags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", flags.join(", "))
+                }
+            );
+        }
+        println!();
+    }
+}
+
+fn sender(message: &ThreadMessage) -> String {
+    match (&message.sender_name, message.sender_id) {
+        (Some(name), _) => name.clone(),
+        (None, Some(id)) => format!("#{id}"),
+        (None, None) => "<unknown>".to_owned(),
+    }
+}
+
+/// Text of the message shortened to its first line, or description of its media or action
+fn first_line(message: &ThreadMessage) -> String {
+    if let Some(ref text) = message.text {
+        let mut lines = text.trim().lines();
+        let mut line = lines.next().unwrap_or_default().trim().to_owned();
+        if lines.next().is_some() {
+            line.push_str(" ...");
+        }
+        return line;
+    }
+    if let Some(ref action) = message.action {
+        return format!("<{}>", action["type"].as_str().unwrap_or("service"));
+    }
+    match message.media_rel_path {
+        Some(ref rel_path) => format!("<media: {rel_path}>"),
+        None => "<empty message>".to_owned(),
+    }
+}
@@ -0,0 +1,191 @@
+use crate::db::Database;
+use crate::render;
+use anyhow::{Context, Result, ensure};
+use grammers_client::grammers_tl_types as tl;
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+#[derive(Serialize)]
+pub struct PollHistory {
+    pub poll_id: i64,
+    /// Question and options of the latest archived version of the poll, if any
+    pub poll: Option<Value>,
+    pub results: Vec<PollResults>,
+}
+
+#[derive(Serialize)]
+pub struct PollResults {
+    pub recorded_at: i64,
+    /// In local time
+    pub time: String,
+    pub total_voters: Option<i32>,
+    /// Vote counts by option
+    pub voters: Value,
+}
+
+#[derive(Serialize)]
+pub struct LocationPoint {
+    pub date: i64,
+    /// In local time
+    pub time: String,
+    pub lat: f64,
+    pub long: f64,
+    pub accuracy_radius: Option<i32>,
+    pub heading: Option<i32>,
+}
+
+/// Every recorded change of poll results
+pub fn poll_history(database: &Database, chat_id: i64, message_id: i32) -> Result<PollHistory> {
+    let poll_id: i64 = database
+        .conn()
+        .query_row(
+            "SELECT poll_id FROM polls WHERE chat_id = ?1 AND message_id = ?2",
+            params![chat_id, message_id],
+            |row| row.get(0),
+        )
+        .optional()?
+        .context("No poll archived for this message")?;
+
+    let poll = match database.find_latest_message(Some(chat_id), message_id)? {
+        Some(tl::enums::Message::Message(tl::types::Message {
+            media: Some(tl::enums::MessageMedia::Poll(ref media)),
+            ..
+        })) => Some(render::poll_json(media)),
+        _ => None,
+    };
+
+    let mut stmt = database.conn().prepare(
+        "SELECT recorded_at, datetime(recorded_at, 'unixepoch', 'localtime'), total_voters, results
+         FROM poll_results WHERE poll_id = ?1 ORDER BY id",
+    )?;
+    let results = stmt
+        .query_map(params![poll_id], |row| {
+            let voters: String = row.get(3)?;
+            Ok(PollResults {
+                recorded_at: row.get(0)?,
+                time: row.get(1)?,
+                total_voters: row.get(2)?,
+                voters: serde_json::from_str(&voters).unwrap_or_default(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to load poll results")?;
+
+    Ok(PollHistory {
+        poll_id,
+        poll,
+        results,
+    })
+}
+
+pub fn print_poll_history(history: &PollHistory) {
+    let question = history
+        .poll
+        .as_ref()
+        .and_then(|p| p["question"].as_str())
+        .unwrap_or("<poll not archived>");
+    println!("Poll {}: {question}", history.poll_id);
+
+    // Options are only known by their bytes in results
+    let option_text = |option: &str| {
+        history
+            .poll
+            .as_ref()
+            .and_then(|p| p["answers"].as_array())
+            .and_then(|answers| {
+                answers
+                    .iter()
+                    .find(|a| a["option"].as_str() == Some(option))
+                    .and_then(|a| a["text"].as_str())
+            })
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("<{option}>"))
+    };
+    for results in &history.results {
+        let voters: Vec<String> = results
+            .voters
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|v| {
+                format!(
+                    "{}: {}",
+                    option_text(v["option"].as_str().unwrap_or_default()),
+                    v["voters"]
+                )
+            })
+            .collect();
+        println!(
+            "{}  total {:>6}  {}",
+            results.time,
+            results
+                .total_voters
+                .map_or("?".to_owned(), |t| t.to_string()),
+            voters.join(" | ")
+        );
+    }
+}
+
+/// Every recorded position of a live location, oldest first
+pub fn location_path(
+    database: &Database,
+    chat_id: i64,
+    message_id: i32,
+) -> Result<Vec<LocationPoint>> {
+    let mut stmt = database.conn().prepare(
+        "SELECT date, datetime(date, 'unixepoch', 'localtime'), lat, long, accuracy_radius, heading
+         FROM live_locations WHERE chat_id = ?1 AND message_id = ?2 ORDER BY date, id",
+    )?;
+    let points = stmt
+        .query_map(params![chat_id, message_id], |row| {
+            Ok(LocationPoint {
+                date: row.get(0)?,
+                time: row.get(1)?,
+                lat: row.get(2)?,
+                long: row.get(3)?,
+                accuracy_radius: row.get(4)?,
+                heading: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to load live location")?;
+    ensure!(
+        !points.is_empty(),
+        "No live location archived for this message"
+    );
+    Ok(points)
+}
+
+/// GeoJSON `Feature` with the path as `LineString`, and times of its points in properties
+pub fn location_path_geojson(points: &[LocationPoint]) -> Value {
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": points.iter().map(|p| [p.long, p.lat]).collect::<Vec<_>>(),
+        },
+        "properties": {
+            "times": points.iter().map(|p| p.date).collect::<Vec<_>>(),
+        },
+    })
+}
+
+pub fn print_location_path(points: &[LocationPoint]) {
+    println!(
+        "{:<19}  {:>10}  {:>11}  {:>8}  {:>7}",
+        "Time", "Latitude", "Longitude", "Accuracy", "Heading"
+    );
+    for point in points {
+        println!(
+            "{:<19}  {:>10.6}  {:>11.6}  {:>8}  {:>7}",
+            point.time,
+            point.lat,
+            point.long,
+            point
+                .accuracy_radius
+                .map_or(String::new(), |r| format!("{r} m")),
+            point.heading.map_or(String::new(), |h| format!("{h}°")),
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use grammers_client::grammers_tl_types::enums::InputFileLocation;
 use grammers_client::{grammers_tl_types as tl, types};
 use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Wrapper around `rpassword::prompt_password` to work
 /// around the issue of not being able to access `/dev/tty`
@@ -140,23 +141,173 @@ impl DateTrait for tl::enums::PhoneCall {
     }
 }
 
+//
+// TextTrait
+//
+
+pub trait TextTrait {
+    /// Message text, [None] if it's empty or not applicable
+    fn text(&self) -> Option<&str>;
+}
+
+impl TextTrait for tl::enums::Message {
+    fn text(&self) -> Option<&str> {
+        match self {
+            tl::enums::Message::Message(msg) if !msg.message.is_empty() => Some(&msg.message),
+            _ => None,
+        }
+    }
+}
+
+//
+// SenderIdTrait
+//
+
+pub trait SenderIdTrait {
+    /// Gets the ID of the message sender, if known.
+    /// Outgoing private messages have no sender specified.
+    fn sender_id(&self) -> Option<i64>;
+}
+
+impl SenderIdTrait for tl::enums::Message {
+    fn sender_id(&self) -> Option<i64> {
+        match self {
+            tl::enums::Message::Message(msg) => msg.from_id.as_ref().or(match msg.peer_id {
+                // In private chats, sender is not specified, and incoming messages come from the peer
+                tl::enums::Peer::User(_) if !msg.out => Some(&msg.peer_id),
+                _ => None,
+            }),
+            tl::enums::Message::Service(msg) => msg.from_id.as_ref(),
+            tl::enums::Message::Empty(_) => None,
+        }
+        .and_then(|peer| peer.chat_id())
+    }
+}
+
 //
 // Other
 //
 
-pub struct DownloadedMedia {
-    pub media_rel_path: String,
-    pub thumbnail_rel_path: Option<String>,
+/// Sticker document of the media with its sticker attribute, if the media is a sticker
+pub fn sticker(
+    media: &tl::enums::MessageMedia,
+) -> Option<(&tl::types::Document, &tl::types::DocumentAttributeSticker)> {
+    let tl::enums::MessageMedia::Document(media) = media else {
+        return None;
+    };
+    let Some(tl::enums::Document::Document(ref document)) = media.document else {
+        return None;
+    };
+    document
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            tl::enums::DocumentAttribute::Sticker(sticker) => Some((document, sticker)),
+            _ => None,
+        })
 }
 
-pub struct NotDownloadable;
+/// ID of the sticker set, if it's referred to by ID
+pub fn sticker_set_id(set: &tl::enums::InputStickerSet) -> Option<i64> {
+    match set {
+        tl::enums::InputStickerSet::Id(set) => Some(set.id),
+        _ => None,
+    }
+}
 
-impl types::Downloadable for NotDownloadable {
-    fn to_raw_input_location(&self) -> Option<InputFileLocation> {
-        None
+/// ID of the photo or document of the media, which unlike the media itself doesn't change
+/// when its file reference is refreshed
+pub fn media_file_id(media: &tl::enums::MessageMedia) -> Option<i64> {
+    match media {
+        tl::enums::MessageMedia::Photo(media) => match media.photo {
+            Some(tl::enums::Photo::Photo(ref photo)) => Some(photo.id),
+            _ => None,
+        },
+        tl::enums::MessageMedia::Document(media) => match media.document {
+            Some(tl::enums::Document::Document(ref document)) => Some(document.id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// TTL of self-destructing (e.g. view-once) media, if the message has such media
+pub fn media_ttl(message: &tl::enums::Message) -> Option<i32> {
+    let tl::enums::Message::Message(message) = message else {
+        return None;
+    };
+    match message.media.as_ref()? {
+        tl::enums::MessageMedia::Photo(media) => media.ttl_seconds,
+        tl::enums::MessageMedia::Document(media) => media.ttl_seconds,
+        _ => None,
     }
 }
 
+/// Human-readable media type
+pub fn describe_media(media: &tl::enums::MessageMedia) -> &'static str {
+    match media {
+        tl::enums::MessageMedia::Photo(_) => "photo",
+        tl::enums::MessageMedia::Document(_) => "document",
+        tl::enums::MessageMedia::Geo(_) => "geo",
+        tl::enums::MessageMedia::Contact(_) => "contact",
+        tl::enums::MessageMedia::Unsupported => "unsupported",
+        tl::enums::MessageMedia::WebPage(_) => "webpage",
+        tl::enums::MessageMedia::Venue(_) => "venue",
+        tl::enums::MessageMedia::Game(_) => "game",
+        tl::enums::MessageMedia::Invoice(_) => "invoice",
+        tl::enums::MessageMedia::GeoLive(_) => "geo live",
+        tl::enums::MessageMedia::Poll(_) => "poll",
+        tl::enums::MessageMedia::Dice(_) => "dice",
+        tl::enums::MessageMedia::Empty => "empty",
+        tl::enums::MessageMedia::Story(_) => "story",
+        tl::enums::MessageMedia::Giveaway(_) => "giveaway",
+        tl::enums::MessageMedia::GiveawayResults(_) => "giveaway results",
+        tl::enums::MessageMedia::PaidMedia(_) => "paid media",
+    }
+}
+
+/// ID of the current profile photo of a user, group or channel, `Some(None)` if it has none.
+/// [None] if it's unknown, e.g. for inaccessible chats and users whose photo isn't included.
+pub fn chat_photo_id(chat: &types::Chat) -> Option<Option<i64>> {
+    let chat_photo = |photo: &tl::enums::ChatPhoto| match photo {
+        tl::enums::ChatPhoto::Photo(photo) => Some(photo.photo_id),
+        tl::enums::ChatPhoto::Empty => None,
+    };
+    match chat {
+        types::Chat::User(user) => match user.raw {
+            tl::enums::User::User(ref user) => match user.photo {
+                Some(tl::enums::UserProfilePhoto::Photo(ref photo)) => Some(Some(photo.photo_id)),
+                Some(tl::enums::UserProfilePhoto::Empty) => Some(None),
+                None => None,
+            },
+            tl::enums::User::Empty(_) => None,
+        },
+        types::Chat::Group(group) => match group.raw {
+            tl::enums::Chat::Chat(ref chat) => Some(chat_photo(&chat.photo)),
+            tl::enums::Chat::Channel(ref channel) => Some(chat_photo(&channel.photo)),
+            _ => None,
+        },
+        types::Chat::Channel(channel) => Some(chat_photo(&channel.raw.photo)),
+    }
+}
+
+/// Lowercase hex representation, e.g. of poll options
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub struct DownloadedMedia {
+    pub media_rel_path: String,
+    pub thumbnail_rel_path: Option<String>,
+}
+
 //
 // Downloadable wrapper for dynamic dispatch
 //
@@ -184,3 +335,40 @@ impl types::Downloadable for DownloadableWrapper {
         self.dl.size()
     }
 }
+
+/// Document that isn't wrapped in message media, e.g. the one of a web page preview
+pub struct RawDocument(pub tl::types::Document);
+
+impl types::Downloadable for RawDocument {
+    fn to_raw_input_location(&self) -> Option<InputFileLocation> {
+        Some(
+            tl::types::InputDocumentFileLocation {
+                id: self.0.id,
+                access_hash: self.0.access_hash,
+                file_reference: self.0.file_reference.clone(),
+                thumb_size: String::new(),
+            }
+            .into(),
+        )
+    }
+
+    fn to_data(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.0.size as usize)
+    }
+}
+
+impl RawDocument {
+    /// Extension of the original file name, if any
+    pub fn extension(&self) -> Option<&str> {
+        self.0.attributes.iter().find_map(|attribute| match attribute {
+            tl::enums::DocumentAttribute::Filename(a) => {
+                std::path::Path::new(&a.file_name).extension()?.to_str()
+            }
+            _ => None,
+        })
+    }
+}
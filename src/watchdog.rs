@@ -0,0 +1,165 @@
+use crate::metrics::METRICS;
+use crate::utils::unix_timestamp;
+use anyhow::{Result, bail};
+use grammers_client::Client;
+use grammers_client::grammers_tl_types as tl;
+use std::sync::atomic::{AtomicI64, AtomicU8, AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Global health state, updated by the watchdog and exposed on `/health`.
+pub static HEALTH: Health = Health::new();
+
+/// How often to check for stalled updates
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait for ping response
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Give up after this many consecutive failed pings
+const MAX_PING_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    /// Updates are flowing
+    Healthy = 0,
+    /// No updates for a while, but Telegram responds to pings
+    Idle = 1,
+    /// Telegram doesn't respond to pings
+    Unhealthy = 2,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Healthy => "healthy",
+            Status::Idle => "idle",
+            Status::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+pub struct Health {
+    status: AtomicU8,
+    started_timestamp: AtomicI64,
+    last_ping_timestamp: AtomicI64,
+    ping_failures: AtomicU32,
+}
+
+impl Health {
+    const fn new() -> Self {
+        Health {
+            status: AtomicU8::new(Status::Healthy as u8),
+            started_timestamp: AtomicI64::new(0),
+            last_ping_timestamp: AtomicI64::new(0),
+            ping_failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self.status.load(Ordering::Relaxed) {
+            0 => Status::Healthy,
+            1 => Status::Idle,
+            _ => Status::Unhealthy,
+        }
+    }
+
+    fn set_status(&self, status: Status) {
+        self.status.store(status as u8, Ordering::Relaxed);
+    }
+
+    /// Renders health state as JSON object.
+    pub fn render(&self) -> String {
+        format!(
+            "{{\"status\":\"{}\",\"started_timestamp\":{},\"last_update_timestamp\":{},\"last_ping_timestamp\":{},\"ping_failures\":{}}}\n",
+            self.status().as_str(),
+            self.started_timestamp.load(Ordering::Relaxed),
+            METRICS.last_update_timestamp.load(Ordering::Relaxed),
+            self.last_ping_timestamp.load(Ordering::Relaxed),
+            self.ping_failures.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Watches for updates stalling.
+///
+/// Telegram stops pushing updates to a session that hasn't made any requests for a while,
+/// and `next_raw_update` never notices that, e.g. after a long network partition.
+/// If no updates were received for `timeout`, the watchdog pings Telegram with `updates.getState`.
+/// A request failing on a dead connection makes `grammers` reconnect.
+///
+/// A successful ping doesn't fetch what was missed. If the update state returned by the ping has moved
+/// since the previous ping, with no updates received in between, updates are stalled.
+///
+/// Returns an error if Telegram doesn't respond for too long, or updates are stalled, so that the process
+/// can be restarted, catching up on missed updates from the saved session state.
+/// Nothing restarts it in-process, running under a supervisor (e.g. systemd `Restart=always`) is required.
+pub async fn run(client: Client, timeout: Duration) -> Result<()> {
+    HEALTH
+        .started_timestamp
+        .store(unix_timestamp(), Ordering::Relaxed);
+    // Returned by the previous successful ping
+    let mut last_state: Option<tl::types::updates::State> = None;
+
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        let last_update = METRICS.last_update_timestamp.load(Ordering::Relaxed);
+        let last_ping = HEALTH.last_ping_timestamp.load(Ordering::Relaxed);
+        let last_activity = HEALTH
+            .started_timestamp
+            .load(Ordering::Relaxed)
+            .max(last_update)
+            .max(last_ping);
+        let silent_for = unix_timestamp() - last_activity;
+        if silent_for < timeout.as_secs() as i64 {
+            if last_update >= last_ping {
+                HEALTH.set_status(Status::Healthy);
+            }
+            continue;
+        }
+
+        log::info!("No updates for {silent_for}s, pinging Telegram");
+        let ping = client.invoke(&tl::functions::updates::GetState {});
+        match tokio::time::timeout(PING_TIMEOUT, ping).await {
+            Ok(Ok(tl::enums::updates::State::State(state))) => {
+                log::info!("Telegram responded, connection is alive");
+                let stalled = last_state.as_ref().filter(|last_state| {
+                    last_update < last_ping && state_moved(last_state, &state)
+                });
+                if let Some(last_state) = stalled {
+                    bail!(
+                        "Update state moved from pts {}, qts {}, seq {} to pts {}, qts {}, seq {} \
+                         without any updates received, updates are stalled",
+                        last_state.pts,
+                        last_state.qts,
+                        last_state.seq,
+                        state.pts,
+                        state.qts,
+                        state.seq
+                    );
+                }
+                last_state = Some(state);
+                HEALTH
+                    .last_ping_timestamp
+                    .store(unix_timestamp(), Ordering::Relaxed);
+                HEALTH.ping_failures.store(0, Ordering::Relaxed);
+                HEALTH.set_status(Status::Idle);
+            }
+            result => {
+                match result {
+                    Ok(Err(e)) => log::warn!("Ping failed: {e}"),
+                    _ => log::warn!("Ping timed out"),
+                }
+                HEALTH.set_status(Status::Unhealthy);
+                let failures = HEALTH.ping_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= MAX_PING_FAILURES {
+                    bail!("Telegram didn't respond to {failures} pings in a row, giving up");
+                }
+            }
+        }
+    }
+}
+
+/// Events happened that should have been pushed as updates. Date is ignored, it moves with time.
+fn state_moved(old: &tl::types::updates::State, new: &tl::types::updates::State) -> bool {
+    (old.pts, old.qts, old.seq) != (new.pts, new.qts, new.seq)
+}